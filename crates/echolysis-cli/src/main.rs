@@ -1,49 +1,195 @@
 use std::{path::PathBuf, str::FromStr, sync::Arc};
 
-use echolysis_core::{engine::Engine, languages::SupportedLanguage};
+use echolysis_core::{
+    engine::{
+        cancel::CancellationToken, limits::Limits, parse_error::ParseErrorPolicy, ranking::Ranking,
+        Engine,
+    },
+    languages::{LanguageConfig, Normalization, SupportedLanguage, Thresholds},
+    utils::encoding::{read_source, ReadError},
+};
 
 pub fn main() {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(8)
-        .build_global()
-        .unwrap();
-
     let start = std::time::Instant::now();
-    let paths = std::env::args()
-        .skip(1)
-        .filter_map(|path| PathBuf::from_str(&path).ok().map(Arc::new))
-        .collect::<Vec<Arc<PathBuf>>>();
-    let sources = paths
-        .iter()
-        .filter_map(|path| std::fs::read_to_string(path.as_path()).ok().map(Arc::new))
-        .collect::<Vec<_>>();
-    let sources = paths
-        .into_iter()
-        .zip(sources.iter().cloned())
-        .collect::<Vec<_>>();
-    let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
-    engine.insert_many(sources);
+    let mut near_miss = None;
+    let mut verify = false;
+    let mut consistent_renaming = false;
+    let mut cache_dir = None;
+    // Zero lets rayon pick, which is one thread per CPU unless `RAYON_NUM_THREADS` is set
+    let mut threads = 0;
+    let mut cancel = CancellationToken::new();
+    let mut config = LanguageConfig::default();
+    let mut ranking = Ranking::default();
+    let mut limits = Limits::default();
+    let mut parse_error_policy = ParseErrorPolicy::default();
+    let mut kind_thresholds = vec![];
+    let mut paths: Vec<Arc<PathBuf>> = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--verify" => verify = true,
+            "--consistent-renaming" => consistent_renaming = true,
+            "--cache" => cache_dir = args.next().map(PathBuf::from),
+            "--timeout" => {
+                if let Some(secs) = args.next().and_then(|x| x.parse::<f64>().ok()) {
+                    cancel = cancel.with_timeout(std::time::Duration::from_secs_f64(secs));
+                }
+            }
+            "--max-bytes" => limits.max_bytes = args.next().and_then(|x| x.parse().ok()),
+            "--max-line-length" => {
+                limits.max_line_length = args.next().and_then(|x| x.parse().ok())
+            }
+            "--max-nodes" => limits.max_nodes = args.next().and_then(|x| x.parse().ok()),
+            "--keep-minified" => limits.skip_minified = false,
+            "--parse-errors" => {
+                parse_error_policy = match args.next().as_deref() {
+                    Some("skip-file") => ParseErrorPolicy::SkipFile,
+                    Some("index") => ParseErrorPolicy::Index,
+                    _ => ParseErrorPolicy::SkipSubtrees,
+                }
+            }
+            "--threads" => {
+                if let Some(n) = args.next().and_then(|x| x.parse::<usize>().ok()) {
+                    threads = n;
+                }
+            }
+            "--seed" => {
+                if let Some(seed) = args.next().and_then(|x| x.parse::<u64>().ok()) {
+                    config.seed = seed;
+                }
+            }
+            "--normalize" => {
+                config.normalization = match args.next().as_deref() {
+                    Some("exact") => Normalization::Exact,
+                    Some("abstracted") => Normalization::Abstracted,
+                    _ => Normalization::Renamed,
+                }
+            }
+            "--abstract-literals" => config.abstract_literals = true,
+            "--thresholds" => {
+                if let Some(spec) = args.next() {
+                    config.thresholds = Some(parse_thresholds(&spec, Thresholds::default()));
+                }
+            }
+            // `KIND:SPEC`, applied over the general thresholds once all arguments are read
+            "--kind-thresholds" => {
+                if let Some((kind, spec)) = args.next().as_deref().and_then(|x| x.split_once(':')) {
+                    kind_thresholds.push((kind.to_string(), spec.to_string()));
+                }
+            }
+            "--near-miss" => near_miss = args.next().and_then(|x| x.parse::<f64>().ok()),
+            "--rank" => {
+                ranking = match args.next().as_deref() {
+                    Some("complexity") => Ranking::Complexity,
+                    Some("tokens") => Ranking::Tokens,
+                    _ => Ranking::Lines,
+                }
+            }
+            _ => paths.extend(PathBuf::from_str(&arg).ok().map(Arc::new)),
+        }
+    }
+    let base = config.thresholds.unwrap_or_default();
+    for (kind, spec) in kind_thresholds {
+        config
+            .kind_thresholds
+            .insert(kind, parse_thresholds(&spec, base));
+    }
+    let mut sources = vec![];
+    let mut unreadable = vec![];
+    let mut too_large = vec![];
+    for path in paths {
+        match read_source(&path, &limits) {
+            Ok(source) => sources.push((path, Arc::new(source))),
+            Err(ReadError::Skipped(reason)) => too_large.push((path, reason)),
+            Err(ReadError::Unreadable(err)) => unreadable.push((path, err)),
+        }
+    }
+    let mut builder =
+        Engine::builder(SupportedLanguage::from_language_id_with_config("rust", config).unwrap())
+            .verify(verify)
+            .consistent_renaming(consistent_renaming)
+            .limits(limits)
+            .parse_error_policy(parse_error_policy)
+            .thread_pool(Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap(),
+            ));
+    if let Some(cache_dir) = cache_dir {
+        builder = builder.cache_dir(cache_dir);
+    }
+    let engine = builder.build();
+    let outcome = engine.insert_many_cancellable(sources, &cancel);
+    let mut complete = outcome.is_complete();
+    let mut skipped = outcome.into_inner();
+    skipped.extend(too_large);
     let indexed = std::time::Instant::now();
 
     let detecting = std::time::Instant::now();
-    let duplicates = engine.detect_duplicates(None);
+    let outcome = match near_miss {
+        Some(similarity) => {
+            engine.detect_near_duplicates_cancellable(similarity, ranking, None, &cancel)
+        }
+        None => engine.detect_duplicates_cancellable(ranking, None, &cancel),
+    };
+    complete &= outcome.is_complete();
+    let mut duplicates = outcome.into_inner();
+    if near_miss.is_none() {
+        let outcome = engine.detect_duplicate_sequences_cancellable(ranking, None, &cancel);
+        complete &= outcome.is_complete();
+        duplicates.extend(outcome.into_inner());
+    }
     let dtected = std::time::Instant::now();
 
     for dup in &duplicates {
         println!("=======================================================");
+        println!(
+            "{:016x} {} ({}): {} lines, {} tokens, complexity {}",
+            dup.id(),
+            dup.kind(),
+            dup.language(),
+            dup.lines(),
+            dup.tokens(),
+            dup.complexity(),
+        );
+        let similarity = dup.similarity();
+        println!(
+            "{} clone, {:.0}% similar, {} differing tokens",
+            similarity.kind(),
+            similarity.score() * 100.0,
+            similarity.differing_leaf_count(),
+        );
+        for (i, literals) in similarity.differing_literals().iter().enumerate() {
+            let texts: Vec<_> = literals.iter().map(|literal| literal.text()).collect();
+            println!("literal {}: {}", i + 1, texts.join(" | "));
+        }
+        for (i, renaming) in similarity.renamings().iter().enumerate().skip(1) {
+            match renaming {
+                Some(renaming) if renaming.is_empty() => {}
+                Some(renaming) => {
+                    let pairs: Vec<_> = renaming
+                        .iter()
+                        .map(|(from, to)| format!("{from} -> {to}"))
+                        .collect();
+                    println!("renaming {}: {}", i + 1, pairs.join(", "));
+                }
+                None => println!("renaming {}: inconsistent", i + 1),
+            }
+        }
         let len = dup.len();
-        for (i, node) in dup.iter().enumerate() {
-            let (start, end) = node.position_range();
+        for (i, fragment) in dup.fragments().iter().enumerate() {
+            let (start, _) = fragment.position_range();
             println!(
                 "{}:{} {} lines long",
-                node.path().to_str().unwrap_or_default(),
+                fragment.path().to_str().unwrap_or_default(),
                 start.row + 1,
-                end.row - start.row + 1,
+                fragment.lines(),
             );
             for _ in 0..start.column {
                 print!(" ");
             }
-            println!("{}", node.text());
+            println!("{}", fragment.text());
             if i != len - 1 {
                 println!("-------------------------------------------------------");
             }
@@ -51,6 +197,25 @@ pub fn main() {
     }
     println!("#######################################################");
     println!("duplicates: {}", duplicates.len());
+    let mut parse_errors = engine.parse_errors();
+    parse_errors.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+    for (path, errors) in &parse_errors {
+        for error in errors.iter() {
+            println!("{}: {error}", path.to_str().unwrap_or_default());
+        }
+    }
+    for (path, err) in &unreadable {
+        println!(
+            "skipped {}: it could not be read: {err}",
+            path.to_str().unwrap_or_default()
+        );
+    }
+    for (path, reason) in &skipped {
+        println!("skipped {}: it {reason}", path.to_str().unwrap_or_default());
+    }
+    if !complete {
+        println!("timed out, results are partial");
+    }
     println!(
         "indexing cost: {} ms",
         indexed.duration_since(start).as_millis()
@@ -60,3 +225,21 @@ pub fn main() {
         dtected.duration_since(detecting).as_millis()
     );
 }
+
+/// Parses comma separated minimums like `complexity=5,lines=10` over `base`, ignoring the
+/// ones that are not understood
+fn parse_thresholds(spec: &str, base: Thresholds) -> Thresholds {
+    let mut thresholds = base;
+    for (key, value) in spec.split(',').filter_map(|x| x.split_once('=')) {
+        match key.trim() {
+            "complexity" => {
+                thresholds.complexity = value.trim().parse().unwrap_or(thresholds.complexity)
+            }
+            "lines" => thresholds.lines = value.trim().parse().unwrap_or(thresholds.lines),
+            "tokens" => thresholds.tokens = value.trim().parse().unwrap_or(thresholds.tokens),
+            "nodes" => thresholds.nodes = value.trim().parse().unwrap_or(thresholds.nodes),
+            _ => {}
+        }
+    }
+    thresholds
+}
@@ -0,0 +1,130 @@
+//! Indexing many small files, with and without reusing parsers and indexing space across them
+//!
+//! The `parse` group measures setting up a parser per file on its own. The `insert` group
+//! measures whole inserts by engines that keep parsers and indexing space for reuse and by
+//! engines that do not, see `EngineBuilder::pooling`, where hashing and indexing the nodes
+//! weigh in too.
+//!
+//! Run with `cargo bench -p echolysis-core --bench insert`.
+
+use std::{path::PathBuf, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use echolysis_core::{engine::Engine, languages::SupportedLanguage};
+
+/// How many times the sources of this crate are indexed, each time under other paths
+const COPIES: usize = 50;
+
+/// The Rust sources of this crate
+fn sources() -> Vec<String> {
+    let mut res = vec![];
+    let mut dirs = vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src")];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                res.push(std::fs::read_to_string(path).unwrap());
+            }
+        }
+    }
+    res
+}
+
+fn corpus() -> Vec<(Arc<PathBuf>, Arc<String>)> {
+    let sources = sources();
+    (0..COPIES)
+        .flat_map(|copy| {
+            sources.iter().enumerate().map(move |(i, source)| {
+                let path = PathBuf::from(format!("copy{copy}/file{i}.rs"));
+                (Arc::new(path), Arc::new(source.clone()))
+            })
+        })
+        .collect()
+}
+
+fn language() -> SupportedLanguage {
+    SupportedLanguage::from_language_id("rust").unwrap()
+}
+
+/// An engine setting up a parser and indexing space for every file
+fn unpooled_engine() -> Engine {
+    Engine::builder(language()).pooling(false).build()
+}
+
+fn parse(c: &mut Criterion) {
+    let language = language();
+    let corpus = corpus();
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("parser_per_file", |b| {
+        b.iter(|| {
+            for (_, source) in &corpus {
+                language.parser().parse(source.as_str(), None).unwrap();
+            }
+        })
+    });
+    group.bench_function("reused_parser", |b| {
+        let mut parser = language.parser();
+        b.iter(|| {
+            for (_, source) in &corpus {
+                parser.parse(source.as_str(), None).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn insert(c: &mut Criterion) {
+    let corpus = corpus();
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    group.bench_function("insert_many", |b| {
+        b.iter_batched(
+            || (Engine::new(language()), corpus.clone()),
+            |(engine, corpus)| {
+                engine.insert_many(corpus);
+                engine
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("insert_many_unpooled", |b| {
+        b.iter_batched(
+            || (unpooled_engine(), corpus.clone()),
+            |(engine, corpus)| {
+                engine.insert_many(corpus);
+                engine
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("insert_sequentially", |b| {
+        b.iter_batched(
+            || (Engine::new(language()), corpus.clone()),
+            |(engine, corpus)| {
+                for (path, source) in corpus {
+                    engine.insert(path, source).unwrap();
+                }
+                engine
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("insert_sequentially_unpooled", |b| {
+        b.iter_batched(
+            || (unpooled_engine(), corpus.clone()),
+            |(engine, corpus)| {
+                for (path, source) in corpus {
+                    engine.insert(path, source).unwrap();
+                }
+                engine
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, insert);
+criterion_main!(benches);
@@ -0,0 +1,179 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+};
+
+use dashmap::DashMap;
+use rayon::ThreadPool;
+use rustc_hash::{FxBuildHasher, FxHashSet};
+
+use crate::languages::SupportedLanguage;
+
+use super::{cache::Cache, limits::Limits, parse_error::ParseErrorPolicy, pool::Pool, Engine};
+
+/// Configures an [`Engine`] before it is built
+pub struct EngineBuilder {
+    language: SupportedLanguage,
+    verify: bool,
+    consistent_renaming: bool,
+    limits: Limits,
+    parse_error_policy: ParseErrorPolicy,
+    cache_dir: Option<PathBuf>,
+    thread_pool: Option<Arc<ThreadPool>>,
+    pooling: bool,
+}
+
+impl EngineBuilder {
+    pub fn new(language: SupportedLanguage) -> Self {
+        Self {
+            language,
+            verify: false,
+            consistent_renaming: false,
+            limits: Limits::default(),
+            parse_error_policy: ParseErrorPolicy::default(),
+            cache_dir: None,
+            thread_pool: None,
+            pooling: true,
+        }
+    }
+
+    /// Whether nodes sharing a structure hash are compared structurally before they are
+    /// grouped, so that hash collisions never show up as duplicates
+    ///
+    /// This costs a full comparison of every group member against its group and is off by
+    /// default.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Whether members of a group must rename identifiers consistently, so that `a + b * a`
+    /// and `x + y * z` are not grouped even though they hash the same
+    ///
+    /// Groups are split into classes of members that map their identifiers one to one. This
+    /// costs a comparison of every group member against its class and is off by default, in
+    /// which case inconsistent renames are only classified as such, see
+    /// [`CloneKind::InconsistentRename`](super::similarity::CloneKind::InconsistentRename).
+    pub fn consistent_renaming(mut self, consistent_renaming: bool) -> Self {
+        self.consistent_renaming = consistent_renaming;
+        self
+    }
+
+    /// Bounds on the files the engine indexes, [`Limits::default`] unless set
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// What to do with files that did not parse cleanly, [`ParseErrorPolicy::SkipSubtrees`]
+    /// unless set
+    pub fn parse_error_policy(mut self, policy: ParseErrorPolicy) -> Self {
+        self.parse_error_policy = policy;
+        self
+    }
+
+    /// A directory where indexed files are cached across runs, so that inserting a file whose
+    /// content did not change skips parsing
+    ///
+    /// The directory is created when the first entry is written. Any number of engines, even in
+    /// different processes, can share it.
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// The thread pool the engine runs its parallel work in, instead of the global rayon pool
+    ///
+    /// The pool can be shared with other engines and with the embedding application.
+    pub fn thread_pool(mut self, thread_pool: Arc<ThreadPool>) -> Self {
+        self.thread_pool = Some(thread_pool);
+        self
+    }
+
+    /// Whether parsers and the space used while indexing are kept for reuse across inserts
+    ///
+    /// This is on by default. Turning it off sets them up again for every file, so that no
+    /// memory is held for them between inserts.
+    pub fn pooling(mut self, pooling: bool) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    pub fn build(self) -> Engine {
+        Engine {
+            cache: self.cache_dir.map(|dir| Cache::new(dir, &self.language)),
+            language: self.language,
+            verify: self.verify,
+            consistent_renaming: self.consistent_renaming,
+            limits: self.limits,
+            parse_error_policy: self.parse_error_policy,
+            tree_map: DashMap::with_hasher(ahash::RandomState::default()),
+            files: DashMap::with_hasher(FxBuildHasher),
+            hash_map: DashMap::with_hasher(FxBuildHasher),
+            node_hash_map: DashMap::with_hasher(FxBuildHasher),
+            parse_errors: DashMap::with_hasher(ahash::RandomState::default()),
+            next_file_id: AtomicU64::new(0),
+            seeds: DashMap::with_hasher(FxBuildHasher),
+            groups: DashMap::with_hasher(FxBuildHasher),
+            sequence_groups: DashMap::with_hasher(FxBuildHasher),
+            dirty: Mutex::new(FxHashSet::default()),
+            dirty_seeds: Mutex::new(FxHashSet::default()),
+            tracking: AtomicBool::new(false),
+            parsers: Pool::new(self.pooling),
+            scratches: Pool::new(self.pooling),
+            thread_pool: self.thread_pool,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const FUNCTION: &str = r#"
+fn process(items: &[u32], limit: u32) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > limit {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    total
+}
+"#;
+
+    #[test]
+    fn work_runs_in_the_configured_thread_pool() {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let engine = Engine::builder(SupportedLanguage::from_language_id("rust").unwrap())
+            .thread_pool(Arc::new(thread_pool))
+            .build();
+        assert_eq!(engine.install(rayon::current_num_threads), 2);
+        assert!(engine.install(rayon::current_thread_index).is_some());
+
+        engine.insert_many(["a.rs", "b.rs", "c.rs"].map(|path| {
+            (
+                Arc::new(PathBuf::from(path)),
+                Arc::new(FUNCTION.to_string()),
+            )
+        }));
+        let groups = engine.detect_duplicates(Ranking::default(), None);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 3);
+    }
+}
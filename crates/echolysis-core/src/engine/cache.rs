@@ -0,0 +1,547 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tree_sitter::Query;
+
+use crate::{
+    languages::SupportedLanguage,
+    utils::hash::{stable_hash, DEFAULT_SEED},
+};
+
+use super::{
+    indexed_node::{Arena, NodeRecord},
+    indexed_tree::IndexedTree,
+    Engine,
+};
+
+/// Marks cache entries, followed by the version of their layout
+const MAGIC: &[u8; 8] = b"ECHOLYS4";
+
+/// A directory of indexed trees, one entry per source file
+///
+/// An entry holds the nodes of a file in preorder, so that loading it skips parsing and
+/// querying. It is keyed by the canonical path of the file and only used while the content
+/// hash stored in it matches the source, so stale entries are simply rebuilt. Entries only
+/// depend on the grammar and its query, not on the hashing configuration or on how the path
+/// was spelled, which lets the CLI and the language server share a cache directory.
+///
+/// Layout, little endian:
+/// * magic, grammar fingerprint `u64`, canonical path length `u64` and path bytes, content
+///   hash `u64`
+/// * node count `u64`, then for every node in preorder: start and end byte `u32`, subtree
+///   length `u32`, kind `u16`, query index `u16`, flags `u8`
+pub(super) struct Cache {
+    dir: PathBuf,
+    language_id: &'static str,
+    fingerprint: u64,
+    /// Number of node kinds of the grammar, which kind ids of entries must stay below
+    kind_count: u16,
+    /// Number of captures of the query, which query indices of entries must stay below
+    capture_count: u16,
+}
+
+impl Cache {
+    pub(super) fn new(dir: PathBuf, language: &SupportedLanguage) -> Self {
+        let (grammar, query) = (language.language(), language.query());
+        Self {
+            dir,
+            language_id: language.language_id(),
+            fingerprint: fingerprint(language.language_id(), grammar, query),
+            kind_count: grammar.node_kind_count() as u16,
+            capture_count: query.capture_names().len() as u16,
+        }
+    }
+
+    /// The path entries of `path` are keyed by, the same however `path` is spelled
+    ///
+    /// Paths that cannot be canonicalized, like those of files that are gone, are only made
+    /// absolute.
+    fn key(path: &Path) -> PathBuf {
+        std::fs::canonicalize(path)
+            .or_else(|_| std::path::absolute(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    fn entry_path(&self, key: &Path) -> PathBuf {
+        let hash = stable_hash(DEFAULT_SEED, key.as_os_str().as_encoded_bytes());
+        self.dir
+            .join(format!("{}-{:016x}.bin", self.language_id, hash))
+    }
+
+    /// Serializes the records of the file at `key` with content hash `content`
+    fn encode(&self, key: &Path, content: u64, records: &[NodeRecord]) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.put(MAGIC);
+        writer.u64(self.fingerprint);
+        writer.bytes(key.as_os_str().as_encoded_bytes());
+        writer.u64(content);
+        writer.u64(records.len() as u64);
+        for record in records {
+            writer.record(record);
+        }
+        writer.bytes
+    }
+
+    /// Deserializes the records of an entry written by [`Cache::encode`] for the same file,
+    /// content and grammar
+    ///
+    /// # Returns
+    /// The records, or `None` if the entry is for something else, is malformed or its records
+    /// do not form a tree over a source of `source_len` bytes
+    fn decode(
+        &self,
+        bytes: &[u8],
+        key: &Path,
+        content: u64,
+        source_len: usize,
+    ) -> Option<Vec<NodeRecord>> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC
+            || reader.u64()? != self.fingerprint
+            || reader.bytes()? != key.as_os_str().as_encoded_bytes()
+            || reader.u64()? != content
+        {
+            return None;
+        }
+
+        let len = reader.u64()? as usize;
+        let mut records = Vec::with_capacity(len.min(bytes.len()));
+        for _ in 0..len {
+            records.push(reader.record()?);
+        }
+        let known = |record: &NodeRecord| {
+            record.kind < self.kind_count
+                && (record.query_index == NodeRecord::NO_QUERY
+                    || record.query_index < self.capture_count)
+        };
+        if !reader.bytes.is_empty() || !records.iter().all(known) || !nests(&records, source_len) {
+            return None;
+        }
+        Some(records)
+    }
+}
+
+/// Hashes what kind ids and query indices of entries mean, which is only meaningful for the
+/// grammar and query they come from
+///
+/// Every kind is hashed along with its id, and the query by its patterns and captures, so
+/// that a query whose patterns change under the same capture names is told apart too.
+fn fingerprint(language_id: &str, grammar: &tree_sitter::Language, query: &Query) -> u64 {
+    let mut fingerprint = stable_hash(DEFAULT_SEED, language_id.as_bytes());
+    fingerprint = stable_hash(fingerprint, &(grammar.abi_version() as u64).to_le_bytes());
+    for id in 0..grammar.node_kind_count() as u16 {
+        let kind = grammar.node_kind_for_id(id).unwrap_or_default();
+        fingerprint = stable_hash(fingerprint, kind.as_bytes());
+        fingerprint = stable_hash(fingerprint, &[grammar.node_kind_is_named(id) as u8]);
+    }
+    fingerprint = stable_hash(fingerprint, &(query.pattern_count() as u64).to_le_bytes());
+    for pattern in 0..query.pattern_count() {
+        let span = [
+            query.start_byte_for_pattern(pattern),
+            query.end_byte_for_pattern(pattern),
+        ];
+        for byte in span {
+            fingerprint = stable_hash(fingerprint, &(byte as u64).to_le_bytes());
+        }
+    }
+    for name in query.capture_names() {
+        fingerprint = stable_hash(fingerprint, name.as_bytes());
+    }
+    fingerprint
+}
+
+/// Whether `records` form a single tree in preorder over a source of `source_len` bytes
+///
+/// Subtrees must nest, or handles would walk out of the arena.
+fn nests(records: &[NodeRecord], source_len: usize) -> bool {
+    let mut open: Vec<usize> = vec![];
+    for (index, record) in records.iter().enumerate() {
+        while open
+            .last()
+            .is_some_and(|&parent| parent + records[parent].len as usize <= index)
+        {
+            open.pop();
+        }
+        let end = index + record.len as usize;
+        if record.len == 0
+            || record.end_byte as usize > source_len
+            || open
+                .last()
+                .is_some_and(|&parent| parent + (records[parent].len as usize) < end)
+            || (index == 0) != open.is_empty()
+        {
+            return false;
+        }
+        open.push(index);
+    }
+    records
+        .first()
+        .is_some_and(|root| root.len as usize == records.len())
+}
+
+impl Engine {
+    /// Loads the indexed tree of `source` from the cache, if there is an entry for it
+    pub(super) fn load_cached(
+        &self,
+        file: u64,
+        path: &Arc<PathBuf>,
+        source: &Arc<String>,
+    ) -> Option<IndexedTree> {
+        let cache = self.cache.as_ref()?;
+        let key = Cache::key(path);
+        let bytes = std::fs::read(cache.entry_path(&key)).ok()?;
+        let content = stable_hash(DEFAULT_SEED, source.as_bytes());
+        let records = cache.decode(&bytes, &key, content, source.len())?;
+
+        let language = self.language.language().clone();
+        let arena = Arena::new(file, path.clone(), source.clone(), language, records);
+        Some(IndexedTree::from_arena(arena))
+    }
+
+    /// Stores the indexed tree of `source` in the cache, ignoring any failure
+    pub(super) fn store_cached(&self, path: &Path, source: &str, tree: &IndexedTree) {
+        let Some(cache) = self.cache.as_ref() else {
+            return;
+        };
+        let key = Cache::key(path);
+        let content = stable_hash(DEFAULT_SEED, source.as_bytes());
+        let bytes = cache.encode(&key, content, tree.records());
+
+        // Entries are replaced as a whole, so concurrent readers never see a partial entry. The
+        // file id tells apart concurrent writers in this process.
+        let entry = cache.entry_path(&key);
+        let file = tree.root_node().id().file();
+        let tmp = entry.with_extension(format!("{}.{}.tmp", std::process::id(), file));
+        let _ = std::fs::create_dir_all(&cache.dir)
+            .and_then(|_| std::fs::write(&tmp, &bytes))
+            .and_then(|_| std::fs::rename(&tmp, &entry))
+            .inspect_err(|_| {
+                let _ = std::fs::remove_file(&tmp);
+            });
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn put(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.put(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.put(bytes);
+    }
+
+    fn record(&mut self, record: &NodeRecord) {
+        self.u32(record.start_byte);
+        self.u32(record.end_byte);
+        self.u32(record.len);
+        self.u16(record.kind);
+        self.u16(record.query_index);
+        self.u8(record.flags);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (res, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(res)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u64()? as usize;
+        self.take(len)
+    }
+
+    fn record(&mut self) -> Option<NodeRecord> {
+        Some(NodeRecord {
+            start_byte: self.u32()?,
+            end_byte: self.u32()?,
+            len: self.u32()?,
+            kind: self.u16()?,
+            query_index: self.u16()?,
+            flags: self.u8()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use super::*;
+    use crate::{
+        engine::{indexed_node::IndexedNode, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const SOURCE: &str = "fn f(items: &[u32]) -> u32 {\n    // sum\n    items.iter().sum()\n}\n";
+
+    /// A cache directory of its own for every test, removed when dropped
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("echolysis-cache-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn engine(dir: &Dir) -> Engine {
+        Engine::builder(SupportedLanguage::from_language_id("rust").unwrap())
+            .cache_dir(&dir.0)
+            .build()
+    }
+
+    fn nodes(root: &IndexedNode) -> Vec<(u16, (usize, usize), usize)> {
+        let mut nodes = vec![];
+        root.preorder_traverse(|node| {
+            nodes.push((node.kind_id(), node.byte_range(), node.children().count()))
+        });
+        nodes
+    }
+
+    #[test]
+    fn loaded_trees_match_parsed_trees() {
+        let dir = Dir::new("roundtrip");
+        let path = Arc::new(PathBuf::from("a.rs"));
+        let source = Arc::new(SOURCE.to_string());
+        let parsed = engine(&dir);
+        parsed.insert(path.clone(), source.clone()).unwrap();
+
+        let loaded = engine(&dir).load_cached(0, &path, &source).unwrap();
+        let root = loaded.root_node();
+        assert_eq!(
+            nodes(&root),
+            nodes(&parsed.tree_map.get(&path).unwrap().root_node())
+        );
+        assert_eq!(root.id().index(), 0);
+    }
+
+    #[test]
+    fn stale_and_malformed_entries_are_ignored() {
+        let dir = Dir::new("stale");
+        let path = Arc::new(PathBuf::from("a.rs"));
+        let engine = engine(&dir);
+        engine
+            .insert(path.clone(), Arc::new(SOURCE.to_string()))
+            .unwrap();
+
+        let edited = Arc::new(SOURCE.replace("sum", "max"));
+        assert!(engine.load_cached(0, &path, &edited).is_none());
+
+        let entry = engine
+            .cache
+            .as_ref()
+            .unwrap()
+            .entry_path(&Cache::key(&path));
+        let bytes = std::fs::read(&entry).unwrap();
+        std::fs::write(&entry, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(engine
+            .load_cached(0, &path, &Arc::new(SOURCE.to_string()))
+            .is_none());
+    }
+
+    fn cache() -> Cache {
+        let language = SupportedLanguage::from_language_id("rust").unwrap();
+        Cache::new(std::env::temp_dir(), &language)
+    }
+
+    fn record(start_byte: u32, end_byte: u32, len: u32) -> NodeRecord {
+        NodeRecord {
+            start_byte,
+            end_byte,
+            len,
+            kind: 7,
+            query_index: NodeRecord::NO_QUERY,
+            flags: NodeRecord::HAS_ERROR,
+        }
+    }
+
+    /// A root with two children, the first of which has a child of its own
+    fn tree() -> Vec<NodeRecord> {
+        vec![
+            record(0, 10, 4),
+            record(0, 5, 2),
+            record(1, 4, 1),
+            record(6, 10, 1),
+        ]
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let cache = cache();
+        let key = Path::new("/src/lib.rs");
+        let bytes = cache.encode(key, 42, &tree());
+        assert_eq!(cache.decode(&bytes, key, 42, 10), Some(tree()));
+    }
+
+    #[test]
+    fn rejects_entries_for_something_else() {
+        let cache = cache();
+        let key = Path::new("/src/lib.rs");
+        let bytes = cache.encode(key, 42, &tree());
+        assert_eq!(
+            cache.decode(&bytes, Path::new("/src/main.rs"), 42, 10),
+            None
+        );
+        assert_eq!(cache.decode(&bytes, key, 43, 10), None);
+
+        let other = Cache {
+            fingerprint: cache.fingerprint ^ 1,
+            ..self::cache()
+        };
+        assert_eq!(other.decode(&bytes, key, 42, 10), None);
+
+        let mut stale = bytes.clone();
+        stale[..MAGIC.len()].copy_from_slice(b"ECHOLYS0");
+        assert_eq!(cache.decode(&stale, key, 42, 10), None);
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        let cache = cache();
+        let key = Path::new("/src/lib.rs");
+        let bytes = cache.encode(key, 42, &tree());
+        for len in 0..bytes.len() {
+            assert_eq!(cache.decode(&bytes[..len], key, 42, 10), None);
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(cache.decode(&trailing, key, 42, 10), None);
+    }
+
+    #[test]
+    fn accepts_only_nested_subtrees() {
+        assert!(nests(&tree(), 10));
+        assert!(nests(&[record(0, 0, 1)], 0));
+        // Nothing, or a root that does not span every record
+        assert!(!nests(&[], 10));
+        assert!(!nests(&tree()[..3], 10));
+        // A second root
+        assert!(!nests(&[record(0, 5, 1), record(5, 10, 1)], 10));
+        // A child that outgrows its parent
+        let mut records = tree();
+        records[2].len = 2;
+        assert!(!nests(&records, 10));
+        // An empty subtree
+        records = tree();
+        records[3].len = 0;
+        assert!(!nests(&records, 10));
+        // A node past the end of the source
+        assert!(!nests(&tree(), 9));
+    }
+
+    #[test]
+    fn keys_paths_however_they_are_spelled() {
+        let cache = cache();
+        let relative = Path::new("Cargo.toml");
+        let absolute = std::env::current_dir().unwrap().join("src/../Cargo.toml");
+        assert_eq!(
+            cache.entry_path(&Cache::key(relative)),
+            cache.entry_path(&Cache::key(&absolute))
+        );
+    }
+
+    #[test]
+    fn fingerprints_tell_grammars_apart() {
+        let python = SupportedLanguage::from_language_id("python").unwrap();
+        assert_ne!(
+            cache().fingerprint,
+            Cache::new(std::env::temp_dir(), &python).fingerprint
+        );
+        assert_eq!(cache().fingerprint, cache().fingerprint);
+    }
+
+    #[test]
+    fn fingerprints_tell_queries_apart() {
+        let language = SupportedLanguage::from_language_id("rust").unwrap();
+        let grammar = language.language();
+        let query = |source| fingerprint("rust", grammar, &Query::new(grammar, source).unwrap());
+        // The same captures, but of other nodes
+        assert_ne!(
+            query("(identifier) @variable"),
+            query("(identifier) @variable (field_identifier) @variable")
+        );
+        assert_ne!(
+            query("(identifier) @variable"),
+            query("(type_identifier) @variable")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_kinds_and_captures() {
+        let cache = cache();
+        let key = Path::new("/src/lib.rs");
+        let mut records = tree();
+        records[1].kind = cache.kind_count;
+        let bytes = cache.encode(key, 42, &records);
+        assert_eq!(cache.decode(&bytes, key, 42, 10), None);
+
+        records = tree();
+        records[2].query_index = cache.capture_count;
+        let bytes = cache.encode(key, 42, &records);
+        assert_eq!(cache.decode(&bytes, key, 42, 10), None);
+
+        records[2].query_index = cache.capture_count - 1;
+        let bytes = cache.encode(key, 42, &records);
+        assert_eq!(cache.decode(&bytes, key, 42, 10), Some(records));
+    }
+}
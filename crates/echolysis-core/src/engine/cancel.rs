@@ -0,0 +1,193 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Stops a long running operation of an [`Engine`](super::Engine) early, either on request or
+/// once a deadline passes
+///
+/// Clones share the request, so one clone can cancel an operation that was given another.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also cancels once `deadline` passes
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Also cancels once `timeout` elapsed from now
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the operation should stop, because it was cancelled or ran past its deadline
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// What an operation that can be cancelled produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome<T> {
+    /// The operation ran to its end
+    Complete(T),
+    /// The operation was cancelled before it returned, so it may have left out part of its
+    /// work
+    Partial(T),
+}
+
+impl<T> Outcome<T> {
+    /// `Complete(value)` unless `cancel` was cancelled
+    pub(super) fn new(value: T, cancel: &CancellationToken) -> Self {
+        if cancel.is_cancelled() {
+            Self::Partial(value)
+        } else {
+            Self::Complete(value)
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        matches!(self, Self::Complete(_))
+    }
+
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Complete(value) | Self::Partial(value) => value,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Outcome<U> {
+        match self {
+            Self::Complete(value) => Outcome::Complete(f(value)),
+            Self::Partial(value) => Outcome::Partial(f(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::{CancellationToken, Outcome};
+    use crate::{
+        engine::{ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const FUNCTION: &str = r#"
+fn process(items: &[u32], limit: u32) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > limit {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    total
+}
+"#;
+
+    fn sources() -> Vec<(Arc<PathBuf>, Arc<String>)> {
+        ["a.rs", "b.rs"]
+            .map(|path| {
+                (
+                    Arc::new(PathBuf::from(path)),
+                    Arc::new(FUNCTION.to_string()),
+                )
+            })
+            .to_vec()
+    }
+
+    #[test]
+    fn clones_share_cancellation_and_deadlines_cancel() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+
+        let past = CancellationToken::new().with_deadline(Instant::now());
+        assert!(past.is_cancelled());
+        let future = CancellationToken::new().with_timeout(Duration::from_secs(3600));
+        assert!(!future.is_cancelled());
+        assert_eq!(Outcome::new(1, &past), Outcome::Partial(1));
+        assert_eq!(
+            Outcome::new(1, &future).map(|x| x + 1),
+            Outcome::Complete(2)
+        );
+    }
+
+    #[test]
+    fn cancelled_inserts_leave_files_untouched() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let outcome = engine.insert_many_cancellable(sources(), &cancel);
+        assert!(!outcome.is_complete());
+        assert!(engine.tree_map.is_empty());
+        assert!(engine
+            .insert_many_cancellable(sources(), &CancellationToken::new())
+            .is_complete());
+        assert_eq!(engine.tree_map.len(), 2);
+    }
+
+    #[test]
+    fn cancelled_detections_are_partial() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        engine.insert_many(sources());
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let outcome = engine.detect_duplicates_cancellable(Ranking::default(), None, &cancel);
+        assert_eq!(outcome.map(|groups| groups.len()), Outcome::Partial(0));
+        let outcome = engine.detect_duplicates_cancellable(
+            Ranking::default(),
+            None,
+            &CancellationToken::new(),
+        );
+        assert_eq!(outcome.map(|groups| groups.len()), Outcome::Complete(1));
+    }
+
+    #[test]
+    fn cancelled_updates_leave_the_rest_for_the_next_update() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        engine.update_duplicates();
+        engine.insert_many(sources());
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let outcome = engine.update_duplicates_cancellable(&cancel);
+        assert!(!outcome.is_complete());
+        assert!(outcome.into_inner().is_empty());
+        let delta = engine.update_duplicates();
+        assert_eq!(delta.added().len(), 1);
+        assert_eq!(engine.duplicates(Ranking::default(), None).len(), 1);
+    }
+}
@@ -0,0 +1,442 @@
+use std::{path::Path, sync::atomic::Ordering};
+
+use rayon::{iter::Either, prelude::*};
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
+
+use super::{
+    cancel::{CancellationToken, Outcome},
+    detect::Coverage,
+    duplicate_group::DuplicateGroup,
+    indexed_node::{Id, IndexedNode},
+    ranking::Ranking,
+    sequence::Seed,
+    Engine,
+};
+
+/// How the duplicate groups changed since the previous update, see
+/// [`Engine::update_duplicates`]
+///
+/// Groups are matched by their ids.
+#[derive(Default)]
+pub struct GroupDelta {
+    added: Vec<DuplicateGroup>,
+    removed: Vec<DuplicateGroup>,
+    changed: Vec<DuplicateGroup>,
+}
+
+impl GroupDelta {
+    /// Groups that did not exist before
+    pub fn added(&self) -> &[DuplicateGroup] {
+        &self.added
+    }
+
+    /// Groups that no longer exist, as they were last reported
+    pub fn removed(&self) -> &[DuplicateGroup] {
+        &self.removed
+    }
+
+    /// Groups whose members moved, or that gained or lost members, as they are now
+    pub fn changed(&self) -> &[DuplicateGroup] {
+        &self.changed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    fn record(&mut self, old: Vec<DuplicateGroup>, new: Vec<DuplicateGroup>) {
+        let mut old: FxHashMap<_, _> = old.into_iter().map(|group| (group.id(), group)).collect();
+        for group in new {
+            match old.remove(&group.id()) {
+                None => self.added.push(group),
+                Some(old) if locations(&old) != locations(&group) => self.changed.push(group),
+                Some(_) => (),
+            }
+        }
+        self.removed.extend(old.into_values());
+    }
+}
+
+fn locations(group: &DuplicateGroup) -> Vec<(&std::path::Path, (usize, usize))> {
+    group
+        .fragments()
+        .iter()
+        .map(|fragment| (fragment.path(), fragment.byte_range()))
+        .collect()
+}
+
+impl Engine {
+    /// Brings the duplicate groups kept by the engine up to date and reports what changed.
+    ///
+    /// The groups are the ones [`Engine::detect_duplicates`] and
+    /// [`Engine::detect_duplicate_sequences`] find. Only hash buckets touched by inserts and
+    /// removals since the previous update are looked at again, together with the buckets
+    /// nested inside them, whose coverage by larger groups may have changed. Likewise, only the
+    /// runs of statements starting with a pair of statements that occurs in a changed file are
+    /// looked at again. The first update looks at everything.
+    ///
+    /// # Returns
+    /// The groups added, removed and changed since the previous update
+    pub fn update_duplicates(&self) -> GroupDelta {
+        self.update_duplicates_cancellable(&CancellationToken::new())
+            .into_inner()
+    }
+
+    /// Updates the duplicate groups like [`Engine::update_duplicates`] until `cancel` is
+    /// cancelled
+    ///
+    /// Buckets that were not looked at again by then stay due for the next update, so a
+    /// partial delta is followed by the rest of the changes at the next update.
+    pub fn update_duplicates_cancellable(&self, cancel: &CancellationToken) -> Outcome<GroupDelta> {
+        self.install(|| {
+            let (dirty, dirty_seeds): (Vec<u64>, Vec<Seed>) =
+                if self.tracking.swap(true, Ordering::AcqRel) {
+                    (
+                        std::mem::take(&mut *self.dirty.lock().unwrap())
+                            .into_iter()
+                            .collect(),
+                        std::mem::take(&mut *self.dirty_seeds.lock().unwrap())
+                            .into_iter()
+                            .collect(),
+                    )
+                } else {
+                    (
+                        self.hash_map.iter().map(|nodes| *nodes.key()).collect(),
+                        self.seeds
+                            .iter()
+                            .map(|occurrences| *occurrences.key())
+                            .collect(),
+                    )
+                };
+
+            let (updates, pending): (Vec<_>, Vec<_>) = self
+                .affected_buckets(dirty)
+                .into_par_iter()
+                .partition_map(|hash| {
+                    if cancel.is_cancelled() {
+                        Either::Right(hash)
+                    } else {
+                        Either::Left((hash, self.current_groups(hash)))
+                    }
+                });
+            let (seed_updates, pending_seeds): (Vec<_>, Vec<_>) =
+                dirty_seeds.into_par_iter().partition_map(|seed| {
+                    if cancel.is_cancelled() {
+                        Either::Right(seed)
+                    } else {
+                        Either::Left((seed, self.current_sequence_groups(seed)))
+                    }
+                });
+            let complete = pending.is_empty() && pending_seeds.is_empty();
+            self.dirty.lock().unwrap().extend(pending);
+            self.dirty_seeds.lock().unwrap().extend(pending_seeds);
+
+            let mut delta = GroupDelta::default();
+            for (hash, groups) in updates {
+                let old = if groups.is_empty() {
+                    self.groups.remove(&hash).map(|(_, groups)| groups)
+                } else {
+                    self.groups.insert(hash, groups.clone())
+                };
+                delta.record(old.unwrap_or_default(), groups);
+            }
+            for (seed, groups) in seed_updates {
+                let old = if groups.is_empty() {
+                    self.sequence_groups.remove(&seed).map(|(_, groups)| groups)
+                } else {
+                    self.sequence_groups.insert(seed, groups.clone())
+                };
+                delta.record(old.unwrap_or_default(), groups);
+            }
+            if complete {
+                Outcome::Complete(delta)
+            } else {
+                Outcome::Partial(delta)
+            }
+        })
+    }
+
+    /// The duplicate groups as of the last [`Engine::update_duplicates`]
+    ///
+    /// # Arguments
+    /// * `ranking` - How groups are ordered, most valuable first
+    /// * `limitation` - Optional maximum number of duplicate groups to return
+    pub fn duplicates(&self, ranking: Ranking, limitation: Option<usize>) -> Vec<DuplicateGroup> {
+        let groups = self
+            .groups
+            .iter()
+            .flat_map(|groups| groups.value().clone())
+            .chain(
+                self.sequence_groups
+                    .iter()
+                    .flat_map(|groups| groups.value().clone()),
+            )
+            .collect();
+        self.install(|| self.rank(groups, ranking, limitation))
+    }
+
+    /// Records that the buckets of `hashes` gained or lost nodes
+    pub(super) fn mark_dirty(&self, hashes: Vec<u64>) {
+        // Nothing needs to be tracked before the first update, which looks at every bucket
+        if !hashes.is_empty() && self.tracking.load(Ordering::Acquire) {
+            self.dirty.lock().unwrap().extend(hashes);
+        }
+    }
+
+    /// Records that the occurrences of `seeds` changed
+    pub(super) fn mark_dirty_seeds(&self, seeds: Vec<Seed>) {
+        if !seeds.is_empty() && self.tracking.load(Ordering::Acquire) {
+            self.dirty_seeds.lock().unwrap().extend(seeds);
+        }
+    }
+
+    /// Records that the groups with members in the file at `path` may have moved
+    pub(super) fn mark_moved(&self, path: &Path) {
+        let hashes = self
+            .groups
+            .iter()
+            .filter(|groups| {
+                groups
+                    .value()
+                    .iter()
+                    .flat_map(DuplicateGroup::fragments)
+                    .any(|fragment| fragment.path() == path)
+            })
+            .map(|groups| *groups.key())
+            .collect();
+        self.mark_dirty(hashes);
+    }
+
+    /// Extends `dirty` with the buckets of every node below a member of a dirty bucket
+    fn affected_buckets(&self, dirty: Vec<u64>) -> FxHashSet<u64> {
+        let mut res: FxHashSet<_> = dirty.iter().copied().collect();
+        for hash in dirty {
+            let nodes = self
+                .hash_map
+                .get(&hash)
+                .map(|nodes| self.nodes(nodes.iter()))
+                .unwrap_or_default();
+            for node in nodes {
+                for child in IndexedNode::all_children(node) {
+                    if let Some(hash) = self.node_hash_map.get(&child.id()) {
+                        res.insert(*hash);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// The groups of the bucket of `hash` as [`Engine::detect_duplicates`] would report them
+    fn current_groups(&self, hash: u64) -> Vec<DuplicateGroup> {
+        let Some(nodes) = self
+            .hash_map
+            .get(&hash)
+            .filter(|nodes| self.is_duplicated(nodes))
+            .map(|nodes| self.nodes(nodes.iter()))
+        else {
+            return vec![];
+        };
+        let ids: Vec<_> = nodes.iter().map(IndexedNode::id).collect();
+        if self.is_covered_now(&ids) {
+            return vec![];
+        }
+        self.node_groups(hash, nodes)
+    }
+
+    /// The groups of the runs of `seed` as [`Engine::detect_duplicate_sequences`] would
+    /// report them
+    fn current_sequence_groups(&self, seed: Seed) -> Vec<DuplicateGroup> {
+        let Some(occurrences) = self.seeds.get(&seed).map(|occurrences| occurrences.clone()) else {
+            return vec![];
+        };
+        self.seed_groups(&occurrences, |first| self.is_covered_now(first))
+    }
+
+    /// Whether the nodes of `ids` are covered like [`Engine::is_covered`] tells from a full
+    /// coverage, looking up only what encloses them
+    fn is_covered_now(&self, ids: &[Id]) -> bool {
+        let coverage = Coverage::with_hasher(FxBuildHasher);
+        for node in self.nodes(ids) {
+            if let Some(covering) = self.covering(&node) {
+                coverage.insert(node.id(), covering);
+            }
+        }
+        Self::is_covered(ids.iter(), &coverage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{duplicate_group::DuplicateGroup, ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const FUNCTION: &str = r#"
+fn process(items: &[u32], limit: u32) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > limit {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    total
+}
+"#;
+
+    const RUN: &str = r#"
+    let mut total = 0;
+    for item in items {
+        if *item > 10 {
+            for _ in 0..*item {
+                if total % 2 == 0 && *item < 100 {
+                    total += item * 2;
+                }
+            }
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    while total > 100 {
+        total /= 2;
+    }
+"#;
+
+    fn locations(groups: &[DuplicateGroup]) -> Vec<Vec<(PathBuf, (usize, usize))>> {
+        let mut res: Vec<Vec<_>> = groups
+            .iter()
+            .map(|group| {
+                group
+                    .fragments()
+                    .iter()
+                    .map(|fragment| (fragment.path().to_path_buf(), fragment.byte_range()))
+                    .collect()
+            })
+            .collect();
+        res.sort();
+        res
+    }
+
+    fn assert_up_to_date(engine: &Engine) {
+        assert_eq!(
+            locations(&engine.duplicates(Ranking::Lines, None)),
+            locations(&engine.detect_duplicates(Ranking::Lines, None))
+        );
+    }
+
+    fn detected(engine: &Engine) -> Vec<Vec<(PathBuf, (usize, usize))>> {
+        let mut groups = engine.detect_duplicates(Ranking::Lines, None);
+        groups.extend(engine.detect_duplicate_sequences(Ranking::Lines, None));
+        locations(&groups)
+    }
+
+    #[test]
+    fn updates_keep_groups_like_full_detection() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let insert = |path: &str, source: String| {
+            engine
+                .insert(Arc::new(PathBuf::from(path)), Arc::new(source))
+                .unwrap();
+        };
+        insert("a.rs", FUNCTION.to_string());
+        insert("b.rs", FUNCTION.to_string());
+        let delta = engine.update_duplicates();
+        assert_eq!(delta.added().len(), 1);
+        assert_up_to_date(&engine);
+
+        // A third copy changes the members of the group, but not its id
+        insert("c.rs", FUNCTION.to_string());
+        let delta = engine.update_duplicates();
+        assert!(delta.added().is_empty() && delta.removed().is_empty());
+        assert_eq!(locations(delta.changed()).len(), 1);
+        assert_eq!(delta.changed()[0].len(), 3);
+        assert_up_to_date(&engine);
+
+        // Nothing changed since the previous update
+        assert!(engine.update_duplicates().is_empty());
+
+        // Moving a copy within its file
+        insert("a.rs", format!("\n{FUNCTION}"));
+        assert_eq!(engine.update_duplicates().changed().len(), 1);
+        assert_up_to_date(&engine);
+
+        engine.remove(Arc::new(PathBuf::from("b.rs")));
+        engine.remove(Arc::new(PathBuf::from("c.rs")));
+        let delta = engine.update_duplicates();
+        assert_eq!(delta.removed().len(), 1);
+        assert!(engine.duplicates(Ranking::Lines, None).is_empty());
+    }
+
+    #[test]
+    fn updates_keep_node_and_sequence_groups_like_full_detection() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let insert = |path: &str, source: String| {
+            engine
+                .insert(Arc::new(PathBuf::from(path)), Arc::new(source))
+                .unwrap();
+        };
+        insert(
+            "a.rs",
+            format!("fn a(items: &[u32]) {{{RUN}    log(total);\n}}\n"),
+        );
+        insert(
+            "b.rs",
+            format!("fn b(items: &[u32]) {{{RUN}    send(total);\n}}\n"),
+        );
+        engine.update_duplicates();
+        assert_eq!(
+            locations(&engine.duplicates(Ranking::Lines, None)),
+            detected(&engine)
+        );
+        assert!(!detected(&engine).is_empty());
+
+        // A third occurrence of the run, in a file of its own
+        insert(
+            "c.rs",
+            format!("fn c(items: &[u32]) {{{RUN}    drop(total);\n}}\n"),
+        );
+        let delta = engine.update_duplicates();
+        assert!(!delta.is_empty());
+        assert_eq!(
+            locations(&engine.duplicates(Ranking::Lines, None)),
+            detected(&engine)
+        );
+
+        // Inserting again keeps the syntax tree, so that the next insert is an edit moving
+        // the run of a.rs
+        insert(
+            "a.rs",
+            format!("fn a(items: &[u32]) {{{RUN}    log(total);\n}}\n"),
+        );
+        insert(
+            "a.rs",
+            format!("fn a(items: &[u32]) {{\n    init();{RUN}    log(total);\n}}\n"),
+        );
+        engine.update_duplicates();
+        assert_eq!(
+            locations(&engine.duplicates(Ranking::Lines, None)),
+            detected(&engine)
+        );
+
+        engine.remove(Arc::new(PathBuf::from("b.rs")));
+        engine.update_duplicates();
+        assert_eq!(
+            locations(&engine.duplicates(Ranking::Lines, None)),
+            detected(&engine)
+        );
+
+        engine.remove_all();
+        let delta = engine.update_duplicates();
+        assert!(!delta.removed().is_empty());
+        assert!(engine.duplicates(Ranking::Lines, None).is_empty());
+    }
+}
@@ -1,69 +1,346 @@
-use std::sync::Arc;
+use super::{
+    cancel::{CancellationToken, Outcome},
+    duplicate_group::{DuplicateGroup, Origin},
+    fragment::Fragment,
+    indexed_node::{Id, IndexedNode},
+    ranking::Ranking,
+    verify::class_hash,
+    Engine,
+};
 
-use super::{indexed_node::IndexedNode, Engine};
-
-use dashmap::DashSet;
+use dashmap::DashMap;
 use rayon::prelude::*;
 use rustc_hash::{FxBuildHasher, FxHashSet};
 
+/// The innermost duplicated node enclosing a node
+#[derive(Clone, Copy)]
+pub(super) struct Covering {
+    /// Byte length of the enclosing node
+    len: usize,
+    /// Hash of the bucket of the enclosing node
+    hash: u64,
+    /// Number of nodes in that bucket
+    members: usize,
+}
+
+pub(super) type Coverage = DashMap<Id, Covering, FxBuildHasher>;
+
 impl Engine {
     /// Detect duplicate code blocks in the parsed source files.
     ///
+    /// Only maximal clones are reported: a group is left out when each of its members lies
+    /// inside a different member of a larger group with as many members, since the larger
+    /// group already covers every occurrence. With verification enabled, members sharing a
+    /// hash are split into groups of structurally equal members.
+    ///
     /// # Arguments
+    /// * `ranking` - How groups are ordered, most valuable first
     /// * `limitation` - Optional maximum number of duplicate groups to return
     ///
     /// # Returns
-    /// A vector of duplicate node groups, where each group contains identical code blocks
-    pub fn detect_duplicates(&self, limitation: Option<usize>) -> Vec<Vec<Arc<IndexedNode>>> {
-        // First collect all child nodes that are part of larger nodes
-        let child_nodes = self.collect_child_nodes();
+    /// A vector of duplicate groups, where each group contains identical code blocks
+    pub fn detect_duplicates(
+        &self,
+        ranking: Ranking,
+        limitation: Option<usize>,
+    ) -> Vec<DuplicateGroup> {
+        self.detect_duplicates_cancellable(ranking, limitation, &CancellationToken::new())
+            .into_inner()
+    }
 
-        // Then find groups of identical nodes that aren't children of other nodes
-        self.hash_map
-            .par_iter()
-            .filter_map(|nodes| {
-                if nodes.len() < 2 {
-                    None
-                } else {
-                    self.extract_non_child_nodes(&nodes, &child_nodes)
-                }
+    /// Detects duplicates like [`Engine::detect_duplicates`] until `cancel` is cancelled
+    ///
+    /// The groups found by then are returned as a partial outcome. These may include groups
+    /// that a complete run leaves out as covered by larger groups.
+    pub fn detect_duplicates_cancellable(
+        &self,
+        ranking: Ranking,
+        limitation: Option<usize>,
+        cancel: &CancellationToken,
+    ) -> Outcome<Vec<DuplicateGroup>> {
+        self.install(|| {
+            // First find the innermost duplicated node around every node
+            let coverage = self.collect_coverage(cancel);
+
+            // Then find groups of identical nodes that aren't covered by larger groups
+            let groups = self
+                .hash_map
+                .par_iter()
+                .filter(|nodes| {
+                    !cancel.is_cancelled()
+                        && self.is_duplicated(nodes)
+                        && !Self::is_covered(nodes.iter(), &coverage)
+                })
+                .flat_map_iter(|nodes| self.node_groups(*nodes.key(), self.nodes(nodes.iter())))
+                .collect();
+            Outcome::new(self.rank(groups, ranking, limitation), cancel)
+        })
+    }
+
+    /// Builds the groups of a duplicated hash bucket that is not covered by a larger group
+    pub(super) fn node_groups(&self, hash: u64, nodes: Vec<IndexedNode>) -> Vec<DuplicateGroup> {
+        let mut members: Vec<_> = nodes.into_iter().map(Fragment::from).collect();
+        members.sort_by(|lhs, rhs| {
+            (lhs.path(), lhs.byte_range()).cmp(&(rhs.path(), rhs.byte_range()))
+        });
+        self.verified_classes(members, Fragment::nodes)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, group)| group.len() > 1)
+            .map(|(i, group)| {
+                let kind = group[0].nodes()[0].kind();
+                self.duplicate_group(class_hash(hash, i), Origin::Node, kind, group)
             })
-            .take_any(limitation.unwrap_or(usize::MAX))
             .collect()
     }
 
-    /// Collects all child nodes from node groups that have duplicates
-    fn collect_child_nodes(&self) -> DashSet<Arc<IndexedNode>, FxBuildHasher> {
-        let child_nodes = DashSet::with_hasher(FxBuildHasher);
+    /// Whether the nodes of a hash bucket are worth reporting
+    pub(super) fn meets_thresholds(&self, nodes: &FxHashSet<Id>) -> bool {
+        // Nodes sharing a hash share their structure, so one of them stands for all
+        nodes
+            .iter()
+            .find_map(|&id| self.node(id))
+            .is_some_and(|node| self.reaches_thresholds(node.kind(), std::slice::from_ref(&node)))
+    }
+
+    /// Whether the fragment made of the contiguous `nodes` reaches the thresholds of `kind`,
+    /// see [`Language::thresholds_of`](crate::languages::Language::thresholds_of)
+    pub(super) fn reaches_thresholds(&self, kind: &str, nodes: &[IndexedNode]) -> bool {
+        let (Some(first), Some(last)) = (nodes.first(), nodes.last()) else {
+            return false;
+        };
+        let thresholds = self.language.thresholds_of(kind);
+        let complexity: f64 = nodes
+            .iter()
+            .map(|node| self.language.indexed_node_cognitive_complexity(node))
+            .sum();
+        let lines = last.position_range().1.row - first.position_range().0.row + 1;
+        if complexity < thresholds.complexity || lines < thresholds.lines {
+            return false;
+        }
+        // Counting walks the whole fragment, which the default thresholds do not need
+        if thresholds.tokens == 0 && thresholds.nodes == 0 {
+            return true;
+        }
+        let (mut tokens, mut hashed) = (0, 0);
+        for node in nodes {
+            self.structure_hash(node, &mut |node, _| {
+                hashed += 1;
+                if self.is_token(node) {
+                    tokens += 1;
+                }
+            });
+        }
+        tokens >= thresholds.tokens && hashed >= thresholds.nodes
+    }
+
+    /// Whether a hash bucket holds duplicates that are worth reporting
+    pub(super) fn is_duplicated(&self, nodes: &FxHashSet<Id>) -> bool {
+        nodes.len() > 1 && self.meets_thresholds(nodes)
+    }
+
+    /// Maps every node below a member of a duplicated bucket to the innermost such member
+    ///
+    /// Buckets are skipped once `cancel` is cancelled, leaving the coverage incomplete.
+    pub(super) fn collect_coverage(&self, cancel: &CancellationToken) -> Coverage {
+        let coverage = Coverage::with_hasher(FxBuildHasher);
         self.hash_map
             .par_iter()
-            .filter(|nodes| nodes.len() > 1)
+            .filter(|nodes| !cancel.is_cancelled() && self.is_duplicated(nodes))
             .for_each(|nodes| {
-                for node in nodes.value() {
-                    for child in IndexedNode::all_children(node.clone()) {
-                        child_nodes.insert(child.clone());
+                for node in self.nodes(nodes.value()) {
+                    let (start, end) = node.byte_range();
+                    let new = Covering {
+                        len: end - start,
+                        hash: *nodes.key(),
+                        members: nodes.len(),
+                    };
+                    for child in IndexedNode::all_children(node) {
+                        coverage
+                            .entry(child.id())
+                            .and_modify(|covering| {
+                                if new.len < covering.len {
+                                    *covering = new;
+                                }
+                            })
+                            .or_insert(new);
                     }
                 }
             });
-        child_nodes
+        coverage
     }
 
-    /// Extracts nodes that aren't children of other nodes from a group
-    fn extract_non_child_nodes(
-        &self,
-        nodes: &FxHashSet<Arc<IndexedNode>>,
-        child_nodes: &DashSet<Arc<IndexedNode>, FxBuildHasher>,
-    ) -> Option<Vec<Arc<IndexedNode>>> {
-        let group: Vec<_> = nodes
+    /// Finds the innermost duplicated node strictly enclosing `node`, like
+    /// [`Engine::collect_coverage`] does for all nodes at once
+    ///
+    /// This walks down from the root of the file of `node`, so it is only worth it for few
+    /// nodes.
+    pub(super) fn covering(&self, node: &IndexedNode) -> Option<Covering> {
+        let mut current = self.tree_map.get(&node.path().to_path_buf())?.root_node();
+        let (start, end) = node.byte_range();
+        let mut res = None;
+        while current.id() != node.id() {
+            if let Some(hash) = self.node_hash_map.get(&current.id()).map(|hash| *hash) {
+                let members = self
+                    .hash_map
+                    .get(&hash)
+                    .filter(|nodes| self.is_duplicated(nodes))
+                    .map(|nodes| nodes.len());
+                if let Some(members) = members {
+                    let (current_start, current_end) = current.byte_range();
+                    res = Some(Covering {
+                        len: current_end - current_start,
+                        hash,
+                        members,
+                    });
+                }
+            }
+            let child = current.children().find(|child| {
+                let (child_start, child_end) = child.byte_range();
+                child_start <= start && end <= child_end
+            })?;
+            current = child.clone();
+        }
+        res
+    }
+
+    /// Whether the given occurrences are exactly the occurrences induced by one larger group,
+    /// that is, they all lie inside the same duplicated bucket which has as many members
+    ///
+    /// The innermost enclosing bucket is the only candidate: any bucket further out contains
+    /// it in each of its members, so it has at most as many members.
+    pub(super) fn is_covered<'a>(
+        ids: impl ExactSizeIterator<Item = &'a Id>,
+        coverage: &Coverage,
+    ) -> bool {
+        let len = ids.len();
+        let mut res: Option<Covering> = None;
+        for id in ids {
+            let Some(covering) = coverage.get(id).map(|covering| *covering) else {
+                return false;
+            };
+            if res.is_some_and(|res| res.hash != covering.hash) {
+                return false;
+            }
+            res = Some(covering);
+        }
+        res.is_some_and(|covering| covering.members == len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{ranking::Ranking, Engine},
+        languages::{LanguageConfig, SupportedLanguage, Thresholds},
+    };
+
+    const LOOP: &str = r#"
+        for item in items {
+            if *item > limit {
+                while total > 1000 {
+                    total /= 2;
+                }
+            } else if *item == 0 {
+                for _ in 0..limit {
+                    total += 1;
+                }
+            } else {
+                total += item;
+            }
+        }"#;
+
+    /// Kinds of the detected groups along with their sizes, given sources of one file
+    fn detect(source: &str) -> Vec<(&'static str, usize)> {
+        detect_with(LanguageConfig::default(), source)
+    }
+
+    fn detect_with(config: LanguageConfig, source: &str) -> Vec<(&'static str, usize)> {
+        let language = SupportedLanguage::from_language_id_with_config("rust", config).unwrap();
+        let engine = Engine::new(language);
+        engine
+            .insert(
+                Arc::new(PathBuf::from("a.rs")),
+                Arc::new(source.to_string()),
+            )
+            .unwrap();
+        let mut groups: Vec<_> = engine
+            .detect_duplicates(Ranking::default(), None)
             .iter()
-            .filter(|node| !child_nodes.contains(*node))
-            .cloned()
+            .map(|group| (group.kind(), group.len()))
             .collect();
+        groups.sort();
+        groups
+    }
+
+    #[test]
+    fn children_of_every_parent_member_are_suppressed() {
+        let method = format!("    fn process(items: &[u32], limit: u32) {{{LOOP}\n    }}\n");
+        let source = format!("impl A {{\n{method}}}\nimpl B {{\n{method}}}\n");
 
-        if group.len() > 1 {
-            Some(group)
-        } else {
-            None
+        assert_eq!(detect(&source), [("function_item", 2)]);
+    }
+
+    #[test]
+    fn children_occurring_more_often_than_their_parent_are_kept() {
+        let method = format!("    fn process(items: &[u32], limit: u32) {{{LOOP}\n    }}\n");
+        let source = format!(
+            "impl A {{\n{method}}}\nimpl B {{\n{method}}}\nfn other(items: &[u32], limit: u32) {{{LOOP}\n}}\n"
+        );
+
+        assert_eq!(
+            detect(&source),
+            [("for_expression", 3), ("function_item", 2)]
+        );
+    }
+
+    /// Thresholds of `lines` lines and nothing else
+    fn lines(lines: usize) -> Thresholds {
+        Thresholds {
+            complexity: 0.0,
+            lines,
+            ..Thresholds::default()
         }
     }
+
+    #[test]
+    fn kind_thresholds_override_the_others() {
+        let method = "    fn send_pair() {\n        send(1, 2);\n    }\n";
+        let source = format!("impl A {{\n{method}}}\nimpl B {{\n{method}}}\n");
+        assert!(detect(&source).is_empty());
+
+        let mut config = LanguageConfig {
+            thresholds: Some(lines(100)),
+            ..LanguageConfig::default()
+        };
+        assert!(detect_with(config.clone(), &source).is_empty());
+        config
+            .kind_thresholds
+            .insert("function_item".to_string(), lines(3));
+        assert_eq!(detect_with(config, &source), [("function_item", 2)]);
+    }
+
+    #[test]
+    fn kind_thresholds_can_raise_the_defaults() {
+        let method = format!("    fn process(items: &[u32], limit: u32) {{{LOOP}\n    }}\n");
+        let source = format!(
+            "impl A {{\n{method}}}\nimpl B {{\n{method}}}\nfn other(items: &[u32], limit: u32) {{{LOOP}\n}}\n"
+        );
+        let mut config = LanguageConfig::default();
+        config.kind_thresholds.insert(
+            "for_expression".to_string(),
+            Thresholds {
+                tokens: 1000,
+                ..Thresholds::default()
+            },
+        );
+
+        // The loops are too small now, the conditions inside them are not
+        assert_eq!(
+            detect_with(config, &source),
+            [("function_item", 2), ("if_expression", 3)]
+        );
+    }
 }
@@ -0,0 +1,185 @@
+use crate::utils::hash::merge_structure_hash;
+
+use super::{fragment::Fragment, similarity::Similarity, Engine};
+
+/// The detection a duplicate group comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// Identical interesting nodes, see [`Engine::detect_duplicates`]
+    Node,
+    /// Identical runs of statements, see [`Engine::detect_duplicate_sequences`]
+    Sequence,
+    /// Similar interesting nodes, see [`Engine::detect_near_duplicates`]
+    NearMiss,
+}
+
+/// A group of duplicated fragments along with what frontends need to report it
+#[derive(Clone)]
+pub struct DuplicateGroup {
+    id: u64,
+    hash: u64,
+    origin: Origin,
+    kind: &'static str,
+    language: &'static str,
+    fragments: Vec<Fragment>,
+    lines: usize,
+    tokens: usize,
+    complexity: f64,
+    similarity: Similarity,
+}
+
+impl DuplicateGroup {
+    /// Identifies the group across detections, as long as the group hash does not change
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The structure hash shared by the members of the group
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+
+    /// The node kind of the members, or of the node holding them for statement runs
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    /// The language id of the members
+    pub fn language(&self) -> &'static str {
+        self.language
+    }
+
+    /// The members of the group, ordered by location
+    pub fn fragments(&self) -> &[Fragment] {
+        &self.fragments
+    }
+
+    pub fn len(&self) -> usize {
+        self.fragments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Number of lines spanned by the first member
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// Number of leaf tokens of the first member
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    /// Cognitive complexity of the first member
+    pub fn complexity(&self) -> f64 {
+        self.complexity
+    }
+
+    pub fn similarity(&self) -> &Similarity {
+        &self.similarity
+    }
+}
+
+impl Engine {
+    /// Builds a duplicate group, ordering `fragments` by location
+    ///
+    /// # Panics
+    /// Panics if `fragments` is empty
+    pub(super) fn duplicate_group(
+        &self,
+        hash: u64,
+        origin: Origin,
+        kind: &'static str,
+        mut fragments: Vec<Fragment>,
+    ) -> DuplicateGroup {
+        fragments.sort_by(|lhs, rhs| {
+            (lhs.path(), lhs.byte_range()).cmp(&(rhs.path(), rhs.byte_range()))
+        });
+
+        let first = &fragments[0];
+        let complexity = first
+            .nodes()
+            .iter()
+            .map(|node| self.language.indexed_node_cognitive_complexity(node))
+            .sum();
+        let mut tokens = 0;
+        for node in first.nodes() {
+            self.structure_hash(node, &mut |node, _| {
+                if self.is_token(node) {
+                    tokens += 1;
+                }
+            });
+        }
+
+        DuplicateGroup {
+            id: merge_structure_hash(hash, origin as u64),
+            hash,
+            origin,
+            kind,
+            language: self.language.language_id(),
+            lines: first.lines(),
+            tokens,
+            complexity,
+            similarity: self.similarity(&fragments),
+            fragments,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::Origin;
+    use crate::{
+        engine::{ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const METHOD: &str = r#"
+    fn process(items: &[u32], limit: u32) -> u32 {
+        let mut total = 0;
+        for item in items {
+            if *item > limit {
+                total += item * 2;
+            } else if *item == 0 {
+                continue;
+            } else {
+                total += item;
+            }
+        }
+        total
+    }
+"#;
+
+    #[test]
+    fn groups_describe_their_first_member() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let source = format!("impl A {{{METHOD}}}\nimpl B {{{METHOD}}}\n");
+        engine
+            .insert(Arc::new(PathBuf::from("a.rs")), Arc::new(source))
+            .unwrap();
+
+        let groups = engine.detect_duplicates(Ranking::default(), None);
+        assert_eq!(groups.len(), 1);
+        let group = &groups[0];
+        assert_eq!(group.origin(), Origin::Node);
+        assert_eq!(group.kind(), "function_item");
+        assert_eq!(group.language(), "rust");
+        assert_eq!(group.lines(), 13);
+        assert!(group.tokens() > 0 && group.complexity() > 0.0);
+        assert_eq!(group.similarity().score(), 1.0);
+        let starts: Vec<_> = group
+            .fragments()
+            .iter()
+            .map(|fragment| fragment.position_range().0.row)
+            .collect();
+        assert_eq!(starts, [1, 16]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use super::{fragment::Fragment, indexed_node::IndexedNode, Engine};
+
+/// Which of the interesting nodes covering a code range is looked up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Cover {
+    /// The innermost interesting node covering the range
+    #[default]
+    Smallest,
+    /// The outermost interesting node covering the range
+    Largest,
+}
+
+impl Engine {
+    /// Find clones of the code at `byte_range` in the file at `path`.
+    ///
+    /// The range is widened to an interesting node covering it, chosen by `cover`, and every
+    /// other indexed node with the same hash is returned, ordered by location. Unlike detection,
+    /// this ignores the reporting thresholds. With verification enabled, only nodes that are
+    /// structurally equal to the covering node are returned.
+    ///
+    /// # Arguments
+    /// * `path` - Path of an inserted file
+    /// * `byte_range` - Start and end byte of the code to look up
+    /// * `cover` - Which covering interesting node is looked up
+    ///
+    /// # Returns
+    /// The clones of the covering node, or nothing if no interesting node covers the range
+    pub fn find_similar(
+        &self,
+        path: &Path,
+        byte_range: (usize, usize),
+        cover: Cover,
+    ) -> Vec<Fragment> {
+        let Some(node) = self.covering_node(path, byte_range, cover) else {
+            return vec![];
+        };
+        let Some(hash) = self.node_hash_map.get(&node.id()).map(|hash| *hash) else {
+            return vec![];
+        };
+
+        let mut res: Vec<_> = self
+            .hash_map
+            .get(&hash)
+            .map(|nodes| {
+                self.nodes(nodes.iter().filter(|&&id| id != node.id()))
+                    .into_iter()
+                    .filter(|x| !self.verify || self.structurally_equal(x, &node))
+                    .collect()
+            })
+            .unwrap_or_default();
+        res.sort_by(|lhs, rhs| (lhs.path(), lhs.byte_range()).cmp(&(rhs.path(), rhs.byte_range())));
+        res.into_iter().map(Fragment::from).collect()
+    }
+
+    /// Walks down from the root to the indexed nodes covering `byte_range`
+    fn covering_node(
+        &self,
+        path: &Path,
+        (start, end): (usize, usize),
+        cover: Cover,
+    ) -> Option<IndexedNode> {
+        let mut node = self.tree_map.get(&path.to_path_buf())?.root_node();
+        let mut res = None;
+        loop {
+            if self.node_hash_map.contains_key(&node.id()) {
+                res = Some(node.clone());
+                if cover == Cover::Largest {
+                    break;
+                }
+            }
+            let child = node.children().find(|child| {
+                let (child_start, child_end) = child.byte_range();
+                child_start <= start && end <= child_end
+            });
+            match child {
+                Some(child) => node = child.clone(),
+                None => break,
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::Cover;
+    use crate::{
+        engine::{ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const SOURCE: &str = "fn f() {\n    send(1, 2);\n}\nfn g() {\n    send(1, 2);\n}\n";
+
+    fn engine() -> Engine {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        engine
+            .insert(
+                Arc::new(PathBuf::from("a.rs")),
+                Arc::new(SOURCE.to_string()),
+            )
+            .unwrap();
+        engine
+    }
+
+    #[test]
+    fn clones_below_the_reporting_thresholds_are_found() {
+        let engine = engine();
+        assert!(engine
+            .detect_duplicates(Ranking::default(), None)
+            .is_empty());
+
+        let start = SOURCE.find("send").unwrap();
+        let clones =
+            engine.find_similar(&PathBuf::from("a.rs"), (start, start + 4), Cover::Smallest);
+        assert_eq!(clones.len(), 1);
+        assert_eq!(clones[0].text(), "send(1, 2)");
+        assert_eq!(clones[0].byte_range().0, SOURCE.rfind("send").unwrap());
+    }
+
+    #[test]
+    fn largest_cover_looks_up_the_outermost_node() {
+        let engine = engine();
+        let start = SOURCE.find("send").unwrap();
+        // The functions differ by name, so only their calls are clones
+        assert!(engine
+            .find_similar(&PathBuf::from("a.rs"), (start, start + 4), Cover::Largest)
+            .is_empty());
+        // Files that were never inserted have no clones
+        assert!(engine
+            .find_similar(&PathBuf::from("b.rs"), (start, start + 4), Cover::Smallest)
+            .is_empty());
+    }
+}
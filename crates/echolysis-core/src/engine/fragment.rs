@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use super::indexed_node::IndexedNode;
+
+/// A piece of code made up of one node or of a run of contiguous sibling nodes
+#[derive(Clone)]
+pub struct Fragment {
+    nodes: Vec<IndexedNode>,
+}
+
+impl From<IndexedNode> for Fragment {
+    fn from(node: IndexedNode) -> Self {
+        Self { nodes: vec![node] }
+    }
+}
+
+impl Fragment {
+    /// Creates a fragment from contiguous sibling nodes
+    ///
+    /// # Panics
+    /// Panics if `nodes` is empty
+    pub(crate) fn new(nodes: Vec<IndexedNode>) -> Self {
+        assert!(!nodes.is_empty(), "a fragment needs at least one node");
+        Self { nodes }
+    }
+
+    pub fn nodes(&self) -> &[IndexedNode] {
+        &self.nodes
+    }
+
+    fn first(&self) -> &IndexedNode {
+        &self.nodes[0]
+    }
+
+    fn last(&self) -> &IndexedNode {
+        &self.nodes[self.nodes.len() - 1]
+    }
+
+    pub fn path(&self) -> &Path {
+        self.first().path()
+    }
+
+    pub fn text(&self) -> &str {
+        let (start, end) = self.byte_range();
+        self.first().source().get(start..end).unwrap_or_default()
+    }
+
+    pub fn position_range(&self) -> (tree_sitter::Point, tree_sitter::Point) {
+        (
+            self.first().position_range().0,
+            self.last().position_range().1,
+        )
+    }
+
+    /// Number of lines spanned by the fragment
+    pub fn lines(&self) -> usize {
+        let (start, end) = self.position_range();
+        end.row - start.row + 1
+    }
+
+    pub fn byte_range(&self) -> (usize, usize) {
+        (self.first().byte_range().0, self.last().byte_range().1)
+    }
+}
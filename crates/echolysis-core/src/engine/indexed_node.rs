@@ -4,124 +4,456 @@ use std::{
     sync::Arc,
 };
 
-use tree_sitter::Node;
+/// Identifies a node among all trees ever indexed by an engine
+///
+/// Every insert assigns a fresh file id, so nodes of a re-inserted file never share ids with
+/// the nodes they replace. Edits keep the file id, and the nodes an edit did not touch keep
+/// their ids while new ones get numbers that were never used in the file, see
+/// [`Engine::edit`](super::Engine::edit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Id {
+    file: u64,
+    index: usize,
+}
 
-pub type Id = usize;
+impl Id {
+    pub fn new(file: u64, index: usize) -> Self {
+        Self { file, index }
+    }
 
-#[derive(Eq)]
-pub struct IndexedNode {
-    id: Id,
+    /// The id assigned to the file when it was inserted
+    pub fn file(&self) -> u64 {
+        self.file
+    }
+
+    /// The number of the node in its file, which is its preorder index until the file is edited
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// What is stored for every node of a file
+///
+/// Byte offsets are 32 bits wide, so files must be smaller than 4 GiB. Positions are derived
+/// from the line table of the file instead of being stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct NodeRecord {
+    pub(super) start_byte: u32,
+    pub(super) end_byte: u32,
+    /// Number of nodes in the subtree of the node, the node included
+    pub(super) len: u32,
+    pub(super) kind: u16,
+    /// Index of the capture of the node in the language query, [`NodeRecord::NO_QUERY`] if none
+    pub(super) query_index: u16,
+    /// [`NodeRecord::EXTRA`], [`NodeRecord::MISSING`], [`NodeRecord::ERROR`] and
+    /// [`NodeRecord::HAS_ERROR`]
+    pub(super) flags: u8,
+}
+
+impl NodeRecord {
+    pub(super) const NO_QUERY: u16 = u16::MAX;
+
+    pub(super) const EXTRA: u8 = 1;
+    pub(super) const MISSING: u8 = 1 << 1;
+    pub(super) const ERROR: u8 = 1 << 2;
+    /// The node is an error or missing node, or has one in its subtree
+    pub(super) const HAS_ERROR: u8 = 1 << 3;
+
+    pub(super) fn flags(node: &tree_sitter::Node) -> u8 {
+        let mut flags = 0;
+        if node.is_extra() {
+            flags |= Self::EXTRA;
+        }
+        if node.is_missing() {
+            flags |= Self::MISSING;
+        }
+        if node.is_error() {
+            flags |= Self::ERROR;
+        }
+        if node.has_error() {
+            flags |= Self::HAS_ERROR;
+        }
+        flags
+    }
+}
+
+/// The nodes of one file, in preorder, along with what they share
+///
+/// The subtree of a node is the range of nodes starting at it and spanning its `len`, so the
+/// first child of a node follows it and every further child follows the subtree of its
+/// previous sibling.
+pub(super) struct Arena {
+    file: u64,
     path: Arc<PathBuf>,
-    query_index: Option<usize>,
-    children: Vec<Arc<IndexedNode>>,
     source: Arc<String>,
-    kind: u16,
-    language: Arc<tree_sitter::Language>,
-    start: tree_sitter::Point,
-    end: tree_sitter::Point,
-    start_byte: usize,
-    end_byte: usize,
-    is_extra_or_missing_or_error: bool,
+    language: tree_sitter::Language,
+    /// Byte offset of the start of every line
+    line_starts: Vec<u32>,
+    records: Vec<NodeRecord>,
+    /// Number of every node by preorder index, see [`Id::index`], empty while the numbers are
+    /// the preorder indices
+    numbers: Vec<u32>,
+    /// Preorder index of every number, [`Arena::UNUSED`] for numbers of nodes that are gone,
+    /// empty like `numbers`
+    indices: Vec<u32>,
+}
+
+impl Arena {
+    const UNUSED: u32 = u32::MAX;
+
+    pub(super) fn new(
+        file: u64,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        language: tree_sitter::Language,
+        records: Vec<NodeRecord>,
+    ) -> Self {
+        Self::with_numbers(file, path, source, language, records, vec![])
+    }
+
+    /// Builds an arena whose nodes are numbered by `numbers`, by preorder index, which must
+    /// be unique
+    pub(super) fn with_numbers(
+        file: u64,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        language: tree_sitter::Language,
+        records: Vec<NodeRecord>,
+        numbers: Vec<u32>,
+    ) -> Self {
+        let mut indices = vec![];
+        if numbers
+            .iter()
+            .enumerate()
+            .any(|(i, &number)| i as u32 != number)
+        {
+            let len = numbers.iter().max().map_or(0, |&max| max as usize + 1);
+            indices = vec![Self::UNUSED; len];
+            for (index, &number) in numbers.iter().enumerate() {
+                indices[number as usize] = index as u32;
+            }
+        }
+        let numbers = if indices.is_empty() { vec![] } else { numbers };
+        let line_starts = std::iter::once(0)
+            .chain(
+                source
+                    .bytes()
+                    .enumerate()
+                    .filter(|(_, byte)| *byte == b'\n')
+                    .map(|(i, _)| i as u32 + 1),
+            )
+            .collect();
+        Self {
+            file,
+            path,
+            source,
+            language,
+            line_starts,
+            records,
+            numbers,
+            indices,
+        }
+    }
+
+    pub(super) fn file(&self) -> u64 {
+        self.file
+    }
+
+    pub(super) fn path(&self) -> &Arc<PathBuf> {
+        &self.path
+    }
+
+    pub(super) fn records(&self) -> &[NodeRecord] {
+        &self.records
+    }
+
+    /// The number of the node at preorder index `index`
+    pub(super) fn number(&self, index: usize) -> u32 {
+        self.numbers.get(index).copied().unwrap_or(index as u32)
+    }
+
+    /// The smallest number that no node of this arena or of the versions it was edited from
+    /// ever had
+    pub(super) fn next_number(&self) -> u32 {
+        self.indices.len().max(self.records.len()) as u32
+    }
+
+    /// The preorder index of the node numbered `number`, if it is still there
+    fn index_of(&self, number: usize) -> Option<u32> {
+        if self.indices.is_empty() {
+            return (number < self.records.len()).then_some(number as u32);
+        }
+        self.indices
+            .get(number)
+            .copied()
+            .filter(|&index| index != Self::UNUSED)
+    }
+
+    /// The preorder index of the outermost node of `kind` spanning exactly `start..end`
+    pub(super) fn find(&self, (start, end): (usize, usize), kind: u16) -> Option<usize> {
+        // Nodes start in preorder, and the ones starting at the same byte nest
+        let first = self
+            .records
+            .partition_point(|record| (record.start_byte as usize) < start);
+        self.records[first..]
+            .iter()
+            .take_while(|record| record.start_byte as usize == start)
+            .position(|record| record.end_byte as usize == end && record.kind == kind)
+            .map(|i| first + i)
+    }
+
+    fn point(&self, byte: u32) -> tree_sitter::Point {
+        let row = self.line_starts.partition_point(|&start| start <= byte) - 1;
+        tree_sitter::Point::new(row, (byte - self.line_starts[row]) as usize)
+    }
+}
+
+/// A node of an indexed file
+///
+/// This is a handle into the arena of the file, so it is cheap to clone and keeps the whole
+/// file alive.
+#[derive(Clone)]
+pub struct IndexedNode {
+    arena: Arc<Arena>,
+    index: u32,
 }
 
 impl Hash for IndexedNode {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
+        self.id().hash(state);
     }
 }
 
 impl PartialEq for IndexedNode {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
+        self.id() == other.id()
     }
 }
 
+impl Eq for IndexedNode {}
+
 impl IndexedNode {
-    pub fn new(
-        node: Node<'_>,
-        path: Arc<PathBuf>,
-        query_index: Option<usize>,
-        children: Vec<Arc<IndexedNode>>,
-        source: Arc<String>,
-        language: Arc<tree_sitter::Language>,
-    ) -> Self {
-        Self {
-            id: node.id(),
-            path,
-            query_index,
-            children,
-            source,
-            kind: node.kind_id(),
-            start: node.start_position(),
-            end: node.end_position(),
-            start_byte: node.start_byte(),
-            end_byte: node.end_byte(),
-            is_extra_or_missing_or_error: node.is_extra() || node.is_missing() || node.is_error(),
-            language,
+    /// The root node of `arena`, which must not be empty
+    pub(super) fn root(arena: Arc<Arena>) -> Self {
+        Self { arena, index: 0 }
+    }
+
+    /// The node of `arena` with `id`, if it belongs to the arena and is still there
+    pub(super) fn with_id(arena: &Arc<Arena>, id: Id) -> Option<Self> {
+        if id.file != arena.file {
+            return None;
         }
+        let index = arena.index_of(id.index)?;
+        Some(Self {
+            arena: arena.clone(),
+            index,
+        })
+    }
+
+    /// The preorder index of the node in its tree
+    pub(super) fn preorder_index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub(super) fn arena(&self) -> &Arc<Arena> {
+        &self.arena
+    }
+
+    fn record(&self) -> &NodeRecord {
+        &self.arena.records[self.index as usize]
     }
 
     pub fn is_extra_or_missing_or_error(&self) -> bool {
-        self.is_extra_or_missing_or_error
+        self.record().flags & (NodeRecord::EXTRA | NodeRecord::MISSING | NodeRecord::ERROR) != 0
+    }
+
+    pub fn is_extra(&self) -> bool {
+        self.record().flags & NodeRecord::EXTRA != 0
+    }
+
+    /// Whether the parser inserted the node to recover from an error, so it has no text
+    pub fn is_missing(&self) -> bool {
+        self.record().flags & NodeRecord::MISSING != 0
+    }
+
+    /// Whether the node wraps text the parser could not make sense of
+    pub fn is_error(&self) -> bool {
+        self.record().flags & NodeRecord::ERROR != 0
+    }
+
+    /// Whether the node or any node of its subtree is an error or missing node
+    pub fn has_error(&self) -> bool {
+        self.record().flags & NodeRecord::HAS_ERROR != 0
     }
 
-    pub fn kind(&self) -> &str {
-        self.language
-            .node_kind_for_id(self.kind)
+    pub fn kind(&self) -> &'static str {
+        self.arena
+            .language
+            .node_kind_for_id(self.record().kind)
             .unwrap_or_default()
     }
 
+    /// The numeric tree-sitter id of the node kind
+    pub fn kind_id(&self) -> u16 {
+        self.record().kind
+    }
+
     pub fn text(&self) -> &str {
-        self.source
-            .get(self.start_byte..self.end_byte)
-            .unwrap_or_default()
+        let (start, end) = self.byte_range();
+        self.arena.source.get(start..end).unwrap_or_default()
+    }
+
+    /// The whole source of the file this node belongs to
+    pub fn source(&self) -> &str {
+        &self.arena.source
     }
 
     pub fn position_range(&self) -> (tree_sitter::Point, tree_sitter::Point) {
-        (self.start, self.end)
+        let record = self.record();
+        (
+            self.arena.point(record.start_byte),
+            self.arena.point(record.end_byte),
+        )
     }
 
     pub fn byte_range(&self) -> (usize, usize) {
-        (self.start_byte, self.end_byte)
+        let record = self.record();
+        (record.start_byte as usize, record.end_byte as usize)
     }
 
     pub fn id(&self) -> Id {
-        self.id
+        Id::new(
+            self.arena.file,
+            self.arena.number(self.index as usize) as usize,
+        )
     }
 
     pub fn path(&self) -> &Path {
-        &self.path
+        &self.arena.path
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.record().len == 1
     }
 
-    pub fn children(&self) -> &Vec<Arc<IndexedNode>> {
-        &self.children
+    pub fn children(&self) -> impl Iterator<Item = IndexedNode> + '_ {
+        let end = self.index + self.record().len;
+        let mut next = self.index + 1;
+        std::iter::from_fn(move || {
+            if next >= end {
+                return None;
+            }
+            let child = self.at(next);
+            next += child.record().len;
+            Some(child)
+        })
     }
 
     pub fn query_index(&self) -> Option<usize> {
-        self.query_index
+        let query_index = self.record().query_index;
+        (query_index != NodeRecord::NO_QUERY).then_some(query_index as usize)
     }
 
     pub fn preorder_traverse(&self, mut f: impl FnMut(&IndexedNode)) {
-        let mut stack = vec![self];
+        let end = self.index + self.record().len;
+        for index in self.index..end {
+            f(&self.at(index));
+        }
+    }
 
-        while let Some(node) = stack.pop() {
-            f(node);
-            for child in node.children.iter().rev() {
-                stack.push(child);
-            }
+    /// Every node below `node`, in preorder
+    pub fn all_children(node: Self) -> Vec<IndexedNode> {
+        let end = node.index + node.record().len;
+        (node.index + 1..end).map(|index| node.at(index)).collect()
+    }
+
+    fn at(&self, index: u32) -> IndexedNode {
+        Self {
+            arena: self.arena.clone(),
+            index,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
 
-    pub fn all_children(node: Arc<Self>) -> Vec<Arc<IndexedNode>> {
-        let mut stack = vec![node];
-        let mut res = vec![];
+    use tree_sitter::Point;
 
+    use super::{Arena, IndexedNode, NodeRecord};
+    use crate::{engine::Engine, languages::SupportedLanguage};
+
+    fn record(start_byte: u32, end_byte: u32, len: u32) -> NodeRecord {
+        NodeRecord {
+            start_byte,
+            end_byte,
+            len,
+            kind: 0,
+            query_index: NodeRecord::NO_QUERY,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn children_follow_the_subtrees_of_their_previous_siblings() {
+        let language = SupportedLanguage::from_language_id("rust").unwrap();
+        // A root with two children, the first of which has a child of its own
+        let records = vec![
+            record(0, 5, 4),
+            record(0, 2, 2),
+            record(1, 2, 1),
+            record(3, 5, 1),
+        ];
+        let arena = Arena::new(
+            0,
+            Arc::new(PathBuf::from("a.rs")),
+            Arc::new("ab\ncd".to_string()),
+            language.language().clone(),
+            records,
+        );
+        let root = IndexedNode::root(Arc::new(arena));
+
+        let children: Vec<_> = root.children().map(|node| node.id().index()).collect();
+        assert_eq!(children, [1, 3]);
+        assert_eq!(IndexedNode::all_children(root.clone()).len(), 3);
+        let last = root.children().last().unwrap();
+        assert!(last.is_leaf());
+        assert_eq!(last.text(), "cd");
+        assert_eq!(last.position_range(), (Point::new(1, 0), Point::new(1, 2)));
+    }
+
+    #[test]
+    fn nodes_match_the_syntax_tree() {
+        let source = "fn f() {\n    let s = \"é\";\n    g(s);\n}\n";
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let path = Arc::new(PathBuf::from("a.rs"));
+        engine
+            .insert(path.clone(), Arc::new(source.to_string()))
+            .unwrap();
+        let mut indexed = vec![];
+        engine
+            .tree_map
+            .get(&path)
+            .unwrap()
+            .root_node()
+            .preorder_traverse(|node| {
+                indexed.push((node.kind(), node.byte_range(), node.position_range()))
+            });
+
+        let tree = engine.language.parser().parse(source, None).unwrap();
+        let mut expected = vec![];
+        let mut stack = vec![tree.root_node()];
         while let Some(node) = stack.pop() {
-            for child in node.children.iter().rev() {
-                res.push(child.clone());
-                stack.push(child.clone());
-            }
+            expected.push((
+                node.kind(),
+                (node.start_byte(), node.end_byte()),
+                (node.start_position(), node.end_position()),
+            ));
+            let mut cursor = node.walk();
+            let children: Vec<_> = node.children(&mut cursor).collect();
+            stack.extend(children.into_iter().rev());
         }
-        res
+        assert_eq!(indexed, expected);
     }
 }
@@ -1,93 +1,309 @@
 use std::{path::PathBuf, sync::Arc};
 
+use rustc_hash::FxHashMap;
 use tree_sitter::{Query, QueryCursor, StreamingIterator, Tree};
 
-use super::indexed_node::IndexedNode;
+use super::{
+    indexed_node::{Arena, IndexedNode, NodeRecord},
+    sequence::Sequence,
+};
 
 pub struct IndexedTree {
-    root: Arc<IndexedNode>,
+    root: IndexedNode,
+    sequences: Vec<Arc<Sequence>>,
+    /// The syntax tree the nodes were built from, kept for incremental parsing of files that
+    /// are being edited
+    tree: Option<Tree>,
+}
+
+/// What indexing a tree needs apart from the tree, kept across trees so that indexing many
+/// files does not set it up again for each of them
+#[derive(Default)]
+pub(super) struct Scratch {
+    query_cursor: QueryCursor,
+    /// Query capture index by tree-sitter node id
+    captures: FxHashMap<usize, u16>,
+    /// Nodes whose subtree is still being laid out
+    open: Vec<usize>,
+}
+
+/// The subtrees an edited tree took over from the previous version of its file
+pub(super) struct Takeover {
+    /// Preorder indices of the roots of the subtrees in the edited tree
+    pub(super) roots: Vec<usize>,
+    /// Preorder index ranges of the subtrees in the previous tree, in ascending order
+    pub(super) previous: Vec<(usize, usize)>,
 }
 
 impl IndexedTree {
-    pub fn new(path: Arc<PathBuf>, source: Arc<String>, tree: Tree, query: &Query) -> Self {
-        let root_node = Self::build_index_nodes(tree, path, source, query);
-        Self { root: root_node }
+    /// Indexes `tree`, numbering its nodes in preorder under the file id `file`
+    ///
+    /// The syntax tree is only kept if `retain` is set, as it takes several times the memory
+    /// of the index.
+    pub fn new(
+        file: u64,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        tree: Tree,
+        query: &Query,
+        retain: bool,
+    ) -> Self {
+        Self::with_scratch(
+            file,
+            path,
+            source,
+            tree,
+            query,
+            retain,
+            &mut Scratch::default(),
+        )
     }
 
-    pub fn root_node(&self) -> Arc<IndexedNode> {
-        self.root.clone()
+    /// Indexes `tree` like [`IndexedTree::new`], reusing `scratch`
+    pub(super) fn with_scratch(
+        file: u64,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        tree: Tree,
+        query: &Query,
+        retain: bool,
+        scratch: &mut Scratch,
+    ) -> Self {
+        let records = Self::build_records(&tree, &source, query, scratch);
+        let arena = Arena::new(file, path, source, tree.language().to_owned(), records);
+        Self {
+            root: IndexedNode::root(Arc::new(arena)),
+            sequences: vec![],
+            tree: retain.then_some(tree),
+        }
     }
 
-    fn build_index_nodes(
-        tree: Tree,
-        path: Arc<PathBuf>,
+    /// Indexes `tree`, parsed from `source` after an edit of the file of `previous`, taking
+    /// over the subtrees of `previous` that the edit left alone
+    ///
+    /// Taken over nodes keep their numbers and query captures, so only the other nodes are
+    /// queried. The file id stays the same.
+    ///
+    /// # Arguments
+    /// * `changed` - Byte ranges of `source` whose syntax changed or that were edited
+    /// * `to_previous` - Maps a byte offset of `source` outside `changed` to the previous
+    ///   source
+    pub(super) fn edited(
+        previous: &IndexedTree,
         source: Arc<String>,
+        tree: Tree,
+        query: &Query,
+        changed: &[(usize, usize)],
+        to_previous: impl Fn(usize) -> usize,
+        scratch: &mut Scratch,
+    ) -> (Self, Takeover) {
+        let old = previous.root.arena();
+        let Scratch {
+            query_cursor,
+            captures: match_map,
+            open,
+        } = scratch;
+        match_map.clear();
+        open.clear();
+
+        let mut records: Vec<NodeRecord> = vec![];
+        let mut numbers = vec![];
+        let mut next_number = old.next_number();
+        let mut takeover = Takeover {
+            roots: vec![],
+            previous: vec![],
+        };
+        // New nodes by tree-sitter id, which still have to be queried
+        let mut fresh = vec![];
+        let mut cursor = tree.walk();
+        'walk: loop {
+            let node = cursor.node();
+            let index = records.len();
+            open.push(index);
+            let (start, end) = (node.start_byte(), node.end_byte());
+            let untouched = changed
+                .iter()
+                .all(|&(changed_start, changed_end)| end < changed_start || changed_end < start);
+            let taken = untouched
+                .then(|| old.find((to_previous(start), to_previous(end)), node.kind_id()))
+                .flatten();
+            if let Some(old_index) = taken {
+                // Nothing moved inside the subtree, so all of it shifts alike
+                let old_records =
+                    &old.records()[old_index..][..old.records()[old_index].len as usize];
+                let shift = start as i64 - old_records[0].start_byte as i64;
+                records.extend(old_records.iter().map(|record| NodeRecord {
+                    start_byte: (record.start_byte as i64 + shift) as u32,
+                    end_byte: (record.end_byte as i64 + shift) as u32,
+                    ..*record
+                }));
+                numbers.extend((old_index..old_index + old_records.len()).map(|i| old.number(i)));
+                takeover.roots.push(index);
+                takeover.previous.push((old_index, old_records.len()));
+            } else {
+                records.push(NodeRecord {
+                    start_byte: start as u32,
+                    end_byte: end as u32,
+                    len: 0,
+                    kind: node.kind_id(),
+                    query_index: NodeRecord::NO_QUERY,
+                    flags: NodeRecord::flags(&node),
+                });
+                numbers.push(next_number);
+                next_number += 1;
+                fresh.push((node.id(), index, (start, end)));
+                if cursor.goto_first_child() {
+                    continue;
+                }
+            }
+
+            // Close every subtree that ends here, up to the next sibling
+            loop {
+                // SAFETY: The node the cursor is on is always open
+                let index = open.pop().unwrap();
+                records[index].len = (records.len() - index) as u32;
+                if cursor.goto_next_sibling() {
+                    break;
+                }
+                if !cursor.goto_parent() {
+                    break 'walk;
+                }
+            }
+        }
+
+        // Only the new nodes are queried, which all overlap a changed range unless tree-sitter
+        // reported the changes too narrowly
+        let range = changed
+            .iter()
+            .fold(None, |range: Option<(usize, usize)>, &(start, end)| {
+                Some(range.map_or((start, end), |range| (range.0.min(start), range.1.max(end))))
+            })
+            .map(|(start, end)| start.saturating_sub(1)..end + 1)
+            .filter(|range| {
+                fresh
+                    .iter()
+                    .all(|&(_, _, (start, end))| start <= range.end && range.start <= end)
+            })
+            .unwrap_or(0..usize::MAX);
+        query_cursor.set_byte_range(range);
+        drop(cursor);
+        {
+            let mut captures = query_cursor.captures(query, tree.root_node(), source.as_bytes());
+            while let Some((m, _)) = captures.next() {
+                if let Some(capture) = m.captures.last() {
+                    match_map.insert(capture.node.id(), capture.index as u16);
+                }
+            }
+        }
+        for (id, index, _) in fresh {
+            if let Some(&query_index) = match_map.get(&id) {
+                records[index].query_index = query_index;
+            }
+        }
+
+        let arena = Arena::with_numbers(
+            old.file(),
+            old.path().clone(),
+            source,
+            tree.language().to_owned(),
+            records,
+            numbers,
+        );
+        let indexed_tree = Self {
+            root: IndexedNode::root(Arc::new(arena)),
+            sequences: vec![],
+            tree: Some(tree),
+        };
+        (indexed_tree, takeover)
+    }
+
+    /// Wraps the nodes of a file that were indexed before
+    pub(super) fn from_arena(arena: Arena) -> Self {
+        Self {
+            root: IndexedNode::root(Arc::new(arena)),
+            sequences: vec![],
+            tree: None,
+        }
+    }
+
+    pub fn root_node(&self) -> IndexedNode {
+        self.root.clone()
+    }
+
+    /// The records of all nodes, in preorder
+    pub(super) fn records(&self) -> &[NodeRecord] {
+        self.root.arena().records()
+    }
+
+    pub(super) fn sequences(&self) -> &[Arc<Sequence>] {
+        &self.sequences
+    }
+
+    pub(super) fn set_sequences(&mut self, sequences: Vec<Arc<Sequence>>) {
+        self.sequences = sequences;
+    }
+
+    /// The syntax tree of the file, if it was kept
+    pub(super) fn tree(&self) -> Option<&Tree> {
+        self.tree.as_ref()
+    }
+
+    /// Lays out the nodes of `tree` in preorder
+    fn build_records(
+        tree: &Tree,
+        source: &str,
         query: &Query,
-    ) -> Arc<IndexedNode> {
-        let tsnode = tree.root_node();
-        let language = Arc::new(tree.language().to_owned());
+        scratch: &mut Scratch,
+    ) -> Vec<NodeRecord> {
+        let Scratch {
+            query_cursor,
+            captures: match_map,
+            open,
+        } = scratch;
+        match_map.clear();
+        open.clear();
+
         // Get all matches first using streaming iterator
-        let mut query_cursor = QueryCursor::new();
-        let mut captures = query_cursor.captures(query, tsnode, source.as_bytes());
-        let mut match_map = std::collections::HashMap::new();
+        query_cursor.set_byte_range(0..usize::MAX);
+        let mut captures = query_cursor.captures(query, tree.root_node(), source.as_bytes());
         while let Some((m, _)) = captures.next() {
             if let Some(capture) = m.captures.last() {
-                match_map.insert(capture.node.id(), capture.index as usize);
+                match_map.insert(capture.node.id(), capture.index as u16);
             }
         }
 
-        // Stack for traversal: (node, processed)
-        let mut stack = vec![(tsnode, false)];
-        // Map to store node's children
-        let mut children_map = std::collections::HashMap::new();
-
-        let mut result = None;
-        while let Some((node, processed)) = stack.pop() {
-            if !processed {
-                // Push back the node as processed
-                stack.push((node, true));
+        let mut records = vec![];
+        let mut cursor = tree.walk();
+        loop {
+            let node = cursor.node();
+            open.push(records.len());
+            records.push(NodeRecord {
+                start_byte: node.start_byte() as u32,
+                end_byte: node.end_byte() as u32,
+                len: 0,
+                kind: node.kind_id(),
+                query_index: match_map
+                    .get(&node.id())
+                    .copied()
+                    .unwrap_or(NodeRecord::NO_QUERY),
+                flags: NodeRecord::flags(&node),
+            });
+            if cursor.goto_first_child() {
+                continue;
+            }
 
-                // Push all children in reverse order (so they pop in correct order)
-                let mut cursor = node.walk();
-                if cursor.goto_first_child() {
-                    let mut children = vec![];
-                    loop {
-                        children.push(cursor.node());
-                        if !cursor.goto_next_sibling() {
-                            break;
-                        }
-                    }
-                    // Push children in reverse order
-                    for child in children.into_iter().rev() {
-                        stack.push((child, false));
-                    }
+            // Close every subtree that ends here, up to the next sibling
+            loop {
+                // SAFETY: The node the cursor is on is always open
+                let index = open.pop().unwrap();
+                records[index].len = (records.len() - index) as u32;
+                if cursor.goto_next_sibling() {
+                    break;
                 }
-            } else {
-                // All children have been processed, create the node
-                let children = children_map.remove(&node.id()).unwrap_or_default();
-                let query_index = match_map.get(&node.id()).copied();
-                let indexed_node = Arc::new(IndexedNode::new(
-                    node,
-                    path.clone(),
-                    query_index,
-                    children,
-                    source.clone(),
-                    language.clone(),
-                ));
-
-                // Store this node in its parent's children list if it's not the root
-                if let Some(parent_id) = node.parent().map(|n| n.id()) {
-                    children_map
-                        .entry(parent_id)
-                        .or_insert_with(Vec::new)
-                        .push(indexed_node.clone());
+                if !cursor.goto_parent() {
+                    return records;
                 }
-
-                result = Some(indexed_node);
             }
         }
-
-        // SAFETY: We know that the root node is always present
-        result.unwrap()
     }
 }
@@ -1,43 +1,576 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+};
 
-use super::{indexed_tree::IndexedTree, Engine};
+use tree_sitter::{InputEdit, ParseOptions, ParseState, Tree};
+
+use crate::utils::edit::{edited_ranges, input_edit, previous_byte};
+
+use super::{
+    cancel::{CancellationToken, Outcome},
+    indexed_node::Id,
+    indexed_tree::{IndexedTree, Scratch, Takeover},
+    limits::SkipReason,
+    merkle_hash::Reuse,
+    parse_error::{ParseError, ParseErrorPolicy},
+    Engine,
+};
 use rayon::prelude::*;
 
 impl Engine {
+    /// Indexes `sources` like [`Engine::insert`] does one by one
+    ///
+    /// # Returns
+    /// The files that were skipped, with the reason why
     pub fn insert_many(
         &self,
         sources: impl IntoParallelIterator<Item = (Arc<PathBuf>, Arc<String>)>,
-    ) {
-        sources.into_par_iter().for_each(|(path, source)| {
-            self.insert(path, source);
+    ) -> Vec<(Arc<PathBuf>, SkipReason)> {
+        self.insert_many_cancellable(sources, &CancellationToken::new())
+            .into_inner()
+    }
+
+    /// Indexes `sources` like [`Engine::insert_many`] until `cancel` is cancelled
+    ///
+    /// Every file is indexed as a whole or not at all, so files left out, or whose parsing was
+    /// cut short, keep what was indexed for them before. They are not reported as skipped.
+    pub fn insert_many_cancellable(
+        &self,
+        sources: impl IntoParallelIterator<Item = (Arc<PathBuf>, Arc<String>)>,
+        cancel: &CancellationToken,
+    ) -> Outcome<Vec<(Arc<PathBuf>, SkipReason)>> {
+        let sources = sources.into_par_iter();
+        let skipped = self.install(|| {
+            sources
+                .filter(|_| !cancel.is_cancelled())
+                .filter_map(|(path, source)| {
+                    match self.insert_until(path.clone(), source, cancel) {
+                        Outcome::Complete(Err(reason)) => Some((path, reason)),
+                        _ => None,
+                    }
+                })
+                .collect()
         });
+        Outcome::new(skipped, cancel)
     }
 
-    pub fn insert(&self, path: Arc<PathBuf>, source: Arc<String>) -> Option<()> {
-        let mut parser = self.language.parser();
-        let query = self.language.query();
-        let tree = match parser.parse(source.as_str(), None) {
-            Some(tree) => tree,
+    /// Indexes `source` as the content of the file at `path`, replacing what was indexed for
+    /// it before
+    ///
+    /// If the previous syntax tree of the file is known, the file is parsed incrementally from
+    /// it, see [`Engine::edit`]. Syntax trees are only kept for files that were already
+    /// indexed when inserted, as those are likely being edited, so a file opened in an editor
+    /// is parsed incrementally from its second change on. Files beyond the
+    /// [`Limits`](super::limits::Limits) of the engine are skipped, which removes what was
+    /// indexed for them before.
+    pub fn insert(&self, path: Arc<PathBuf>, source: Arc<String>) -> Result<(), SkipReason> {
+        self.insert_until(path, source, &CancellationToken::new())
+            .into_inner()
+    }
+
+    /// Indexes like [`Engine::insert`], giving up without changing anything if `cancel` is
+    /// cancelled while parsing
+    fn insert_until(
+        &self,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        cancel: &CancellationToken,
+    ) -> Outcome<Result<(), SkipReason>> {
+        if let Err(reason) = self.limits.check_source(&source) {
+            return self.skip(path, reason);
+        }
+        let edit = self.tree_map.get(&path).and_then(|tree| {
+            tree.tree()?;
+            Some(input_edit(tree.root_node().source(), &source))
+        });
+        match edit {
+            Some(edit) => self.edit_until(path, source, edit.as_slice(), cancel),
+            None => self.insert_fresh_until(path, source, None, cancel),
+        }
+    }
+
+    /// Indexes `source` from scratch under a fresh file id, parsing it incrementally from
+    /// `old` if given
+    fn insert_fresh_until(
+        &self,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        old: Option<&Tree>,
+        cancel: &CancellationToken,
+    ) -> Outcome<Result<(), SkipReason>> {
+        // A fresh file id keeps the new nodes apart from the nodes of a replaced tree
+        let file = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+        let cached = old
+            .is_none()
+            .then(|| self.load_cached(file, &path, &source))
+            .flatten();
+        let indexed_tree = match cached {
+            Some(indexed_tree) => indexed_tree,
             None => {
-                self.remove(path);
+                let query = self.language.query();
+                let tree = match self.parse_until(&source, old, cancel) {
+                    Outcome::Complete(Ok(tree)) => tree,
+                    Outcome::Complete(Err(reason)) => return self.skip(path, reason),
+                    Outcome::Partial(_) => return Outcome::Partial(Ok(())),
+                };
+                // Files inserted again are likely being edited, so their next version can be
+                // parsed incrementally
+                let retain = self.tree_map.contains_key(&path);
+                let indexed_tree = IndexedTree::with_scratch(
+                    file,
+                    path.clone(),
+                    source.clone(),
+                    tree,
+                    query,
+                    retain,
+                    &mut self.scratches.get(Scratch::default),
+                );
+                if old.is_none() {
+                    self.store_cached(&path, &source, &indexed_tree);
+                }
+                indexed_tree
+            }
+        };
+        // Cache entries may have been stored under other limits
+        if let Err(reason) = self.limits.check_nodes(indexed_tree.records().len()) {
+            return self.skip(path, reason);
+        }
+        Outcome::Complete(self.replace_tree(path, indexed_tree, None))
+    }
+
+    /// Re-indexes the file at `path` after `edits` turned its previous source into `source`
+    ///
+    /// Only the edited parts are parsed again. Subtrees the edits did not touch are taken over
+    /// from the previous version along with their ids and their entries in the index, so only
+    /// the nodes around the edits are hashed and indexed again. Files without a previous
+    /// syntax tree, like files that were inserted only once or were loaded from the cache, are
+    /// indexed from scratch.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the edited file
+    /// * `source` - The whole source after the edits
+    /// * `edits` - The edits in the order they were made, see [`input_edit`] to compute one
+    pub fn edit(
+        &self,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        edits: &[InputEdit],
+    ) -> Result<(), SkipReason> {
+        if let Err(reason) = self.limits.check_source(&source) {
+            return self.skip(path, reason).into_inner();
+        }
+        self.edit_until(path, source, edits, &CancellationToken::new())
+            .into_inner()
+    }
+
+    /// Re-indexes like [`Engine::edit`], giving up without changing anything if `cancel` is
+    /// cancelled while parsing
+    ///
+    /// `source` must be within the limits of the engine.
+    fn edit_until(
+        &self,
+        path: Arc<PathBuf>,
+        source: Arc<String>,
+        edits: &[InputEdit],
+        cancel: &CancellationToken,
+    ) -> Outcome<Result<(), SkipReason>> {
+        let previous = self
+            .tree_map
+            .get(&path)
+            .and_then(|tree| tree.tree().cloned());
+        let Some(mut edited) = previous else {
+            return self.insert_fresh_until(path, source, None, cancel);
+        };
+        for edit in edits {
+            edited.edit(edit);
+        }
+        let tree = match self.parse_until(&source, Some(&edited), cancel) {
+            Outcome::Complete(Ok(tree)) => tree,
+            Outcome::Complete(Err(reason)) => return self.skip(path, reason),
+            Outcome::Partial(_) => return Outcome::Partial(Ok(())),
+        };
+        let changed: Vec<_> = edited
+            .changed_ranges(&tree)
+            .map(|range| (range.start_byte, range.end_byte))
+            .chain(edited_ranges(edits))
+            .collect();
+
+        let built = self.tree_map.get(&path).and_then(|previous| {
+            // Numbers of nodes that are gone are never used again, so once they outnumber the
+            // nodes the file is numbered afresh
+            let arena = previous.root_node().arena().clone();
+            if arena.next_number() as usize > 2 * arena.records().len() {
                 return None;
             }
+            Some(IndexedTree::edited(
+                &previous,
+                source.clone(),
+                tree.clone(),
+                self.language.query(),
+                &changed,
+                |byte| previous_byte(edits, byte),
+                &mut self.scratches.get(Scratch::default),
+            ))
+        });
+        let Some((indexed_tree, takeover)) = built else {
+            return self.insert_fresh_until(path, source, Some(&edited), cancel);
         };
-        let indexed_tree = IndexedTree::new(path.clone(), source, tree, query);
+        Outcome::Complete(self.replace_tree(path, indexed_tree, Some(takeover)))
+    }
+
+    /// Removes what was indexed for the file at `path`, which is skipped for `reason`
+    fn skip(&self, path: Arc<PathBuf>, reason: SkipReason) -> Outcome<Result<(), SkipReason>> {
+        self.remove(path);
+        Outcome::Complete(Err(reason))
+    }
 
+    /// Parses `source` with a pooled parser, reusing the unchanged parts of `old`, which must
+    /// have been edited to match `source`
+    ///
+    /// Parsing halts once `cancel` is cancelled, which is reported as a partial outcome. Trees
+    /// with more nodes than the limits of the engine allow are rejected.
+    fn parse_until(
+        &self,
+        source: &str,
+        old: Option<&Tree>,
+        cancel: &CancellationToken,
+    ) -> Outcome<Result<Tree, SkipReason>> {
+        let mut parser = self.parsers.get(|| self.language.parser());
+        let mut halt = |_: &ParseState| cancel.is_cancelled();
+        let bytes = source.as_bytes();
+        let tree = parser.parse_with_options(
+            &mut |i, _| bytes.get(i..).unwrap_or_default(),
+            old,
+            Some(ParseOptions::new().progress_callback(&mut halt)),
+        );
+        let Some(tree) = tree else {
+            if !cancel.is_cancelled() {
+                return Outcome::Complete(Err(SkipReason::ParseFailed));
+            }
+            // A halted parser would resume its parse on the next call
+            parser.reset();
+            return Outcome::Partial(Err(SkipReason::ParseFailed));
+        };
+        let nodes = tree.root_node().descendant_count();
+        Outcome::Complete(self.limits.check_nodes(nodes).map(|_| tree))
+    }
+
+    /// Indexes `indexed_tree` in place of what was indexed for the file at `path`, unless the
+    /// parse error policy skips it
+    ///
+    /// With `takeover`, the subtrees the tree took over from the previous version of the file
+    /// stay in the index as they are.
+    fn replace_tree(
+        &self,
+        path: Arc<PathBuf>,
+        mut indexed_tree: IndexedTree,
+        takeover: Option<Takeover>,
+    ) -> Result<(), SkipReason> {
+        let mut errors = vec![];
+        ParseError::collect(&indexed_tree.root_node(), &mut errors);
+        if errors.is_empty() {
+            self.parse_errors.remove(&path);
+        } else if self.parse_error_policy == ParseErrorPolicy::SkipFile {
+            self.remove(path.clone());
+            let reason = SkipReason::SyntaxErrors {
+                errors: errors.len(),
+            };
+            self.parse_errors.insert(path, errors.into());
+            return Err(reason);
+        } else {
+            self.parse_errors.insert(path.clone(), errors.into());
+        }
+
+        let arena = indexed_tree.root_node().arena().clone();
         match self.tree_map.entry(path) {
             dashmap::Entry::Occupied(mut entry) => {
                 let old = entry.get();
-                self.remove_merkle_hashes(old.root_node());
-                self.merkle_hash(&indexed_tree);
+                let reuse = match takeover {
+                    Some(takeover) => {
+                        self.remove_merkle_hashes_except(old.root_node(), &takeover.previous);
+                        let taken = takeover
+                            .roots
+                            .iter()
+                            .map(|&index| Id::new(arena.file(), arena.number(index) as usize))
+                            .collect();
+                        let statements = old
+                            .sequences()
+                            .iter()
+                            .flat_map(|sequence| sequence.statement_hashes())
+                            .collect();
+                        // What was taken over moved without being indexed again
+                        self.mark_moved(entry.key());
+                        Some(Reuse { taken, statements })
+                    }
+                    None => {
+                        self.remove_merkle_hashes(old.root_node());
+                        self.files.remove(&old.root_node().id().file());
+                        None
+                    }
+                };
+                self.unindex_sequences(old.sequences());
+                self.files.insert(arena.file(), arena);
+                self.merkle_hash(&mut indexed_tree, reuse.as_ref());
+                self.index_sequences(indexed_tree.sequences());
                 entry.insert(indexed_tree);
             }
             dashmap::Entry::Vacant(entry) => {
-                self.merkle_hash(&indexed_tree);
+                self.files.insert(arena.file(), arena);
+                self.merkle_hash(&mut indexed_tree, None);
+                self.index_sequences(indexed_tree.sequences());
                 entry.insert(indexed_tree);
             }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{
+            duplicate_group::DuplicateGroup, indexed_node::IndexedNode, ranking::Ranking, Engine,
+        },
+        languages::SupportedLanguage,
+    };
+
+    const SOURCE: &str = r#"fn process(items: &[u32], limit: u32) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > limit {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    total
+}
+"#;
+
+    fn root(engine: &Engine, path: &str) -> IndexedNode {
+        engine
+            .tree_map
+            .get(&PathBuf::from(path))
+            .unwrap()
+            .root_node()
+    }
+
+    #[test]
+    fn nodes_are_numbered_in_preorder() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let path = Arc::new(PathBuf::from("a.rs"));
+        engine.insert(path, Arc::new(SOURCE.to_string())).unwrap();
+
+        let mut stack = vec![root(&engine, "a.rs")];
+        let mut expected = 0;
+        while let Some(node) = stack.pop() {
+            assert_eq!(node.id().index(), expected);
+            expected += 1;
+            let children: Vec<_> = node.children().collect();
+            stack.extend(children.into_iter().rev());
+        }
+    }
+
+    #[test]
+    fn identical_files_keep_their_nodes_apart() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        for path in ["a.rs", "b.rs"] {
+            engine
+                .insert(Arc::new(PathBuf::from(path)), Arc::new(SOURCE.to_string()))
+                .unwrap();
+        }
+
+        assert_ne!(root(&engine, "a.rs").id(), root(&engine, "b.rs").id());
+        let groups = engine.detect_duplicates(Ranking::default(), None);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 2);
+    }
+
+    #[test]
+    fn reinserted_files_get_a_fresh_file_id() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let path = Arc::new(PathBuf::from("a.rs"));
+        engine
+            .insert(path.clone(), Arc::new(SOURCE.to_string()))
+            .unwrap();
+        let old = root(&engine, "a.rs").id();
+        engine.insert(path, Arc::new(SOURCE.to_string())).unwrap();
+
+        assert_ne!(root(&engine, "a.rs").id().file(), old.file());
+    }
+
+    /// A source with two copies of a function, which stay apart from the edited one
+    const FUNCTIONS: &str = r#"
+fn first(items: &[u32]) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > 10 {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    total
+}
+
+fn middle(value: u32) -> u32 {
+    let doubled = value * 2;
+    doubled + 1
+}
+
+fn last(items: &[u32]) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > 10 {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    total
+}
+"#;
+
+    /// Every indexed node by hash and location
+    fn index(engine: &Engine) -> Vec<(u64, (usize, usize))> {
+        let mut res: Vec<_> = engine
+            .hash_map
+            .iter()
+            .flat_map(|nodes| {
+                let hash = *nodes.key();
+                engine
+                    .nodes(nodes.value())
+                    .into_iter()
+                    .map(move |node| (hash, node.byte_range()))
+            })
+            .collect();
+        res.sort();
+        res
+    }
+
+    fn locations(groups: Vec<DuplicateGroup>) -> Vec<Vec<(usize, usize)>> {
+        let mut res: Vec<_> = groups
+            .iter()
+            .map(|group| group.fragments().iter().map(|f| f.byte_range()).collect())
+            .collect();
+        res.sort();
+        res
+    }
+
+    fn function(engine: &Engine, path: &PathBuf, name: &str) -> IndexedNode {
+        let root = engine.tree_map.get(path).unwrap().root_node();
+        let function = root
+            .children()
+            .find(|node| node.text().starts_with(&format!("fn {name}")))
+            .unwrap();
+        function
+    }
+
+    #[test]
+    fn edits_index_like_fresh_inserts_and_keep_untouched_ids() {
+        let path = Arc::new(PathBuf::from("lib.rs"));
+        let edited_engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        // The second insert keeps the syntax tree, so the third one is an edit
+        for _ in 0..2 {
+            edited_engine
+                .insert(path.clone(), Arc::new(FUNCTIONS.to_string()))
+                .unwrap();
+        }
+        edited_engine.update_duplicates();
+        let before = function(&edited_engine, &path, "last").id();
+
+        let edited = FUNCTIONS.replace(
+            "doubled + 1",
+            "let tripled = value * 3;\n    doubled + tripled",
+        );
+        edited_engine
+            .insert(path.clone(), Arc::new(edited.clone()))
+            .unwrap();
+        let fresh_engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        fresh_engine.insert(path.clone(), Arc::new(edited)).unwrap();
+
+        assert_eq!(function(&edited_engine, &path, "last").id(), before);
+        assert_eq!(index(&edited_engine), index(&fresh_engine));
+        assert_eq!(
+            locations(edited_engine.detect_duplicates(Ranking::Lines, None)),
+            locations(fresh_engine.detect_duplicates(Ranking::Lines, None))
+        );
+        assert_eq!(
+            locations(edited_engine.detect_duplicate_sequences(Ranking::Lines, None)),
+            locations(fresh_engine.detect_duplicate_sequences(Ranking::Lines, None))
+        );
+        // Groups that only moved are brought up to date too
+        edited_engine.update_duplicates();
+        let mut detected = fresh_engine.detect_duplicates(Ranking::Lines, None);
+        detected.extend(fresh_engine.detect_duplicate_sequences(Ranking::Lines, None));
+        assert_eq!(
+            locations(edited_engine.duplicates(Ranking::Lines, None)),
+            locations(detected)
+        );
+    }
+
+    #[test]
+    fn edits_without_a_previous_tree_index_from_scratch() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let path = Arc::new(PathBuf::from("lib.rs"));
+        engine
+            .edit(path.clone(), Arc::new(FUNCTIONS.to_string()), &[])
+            .unwrap();
+
+        assert_eq!(
+            locations(engine.detect_duplicates(Ranking::Lines, None)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn pooled_scratch_space_leaves_nothing_behind() {
+        let shapes = |engine: &Engine, path: &str| {
+            let mut shapes = vec![];
+            root(engine, path).preorder_traverse(|node| {
+                shapes.push((node.kind(), node.byte_range(), node.query_index()))
+            });
+            shapes
+        };
+        let pooled = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        for (path, source) in [("a.rs", FUNCTIONS), ("b.rs", SOURCE)] {
+            pooled
+                .insert(Arc::new(PathBuf::from(path)), Arc::new(source.to_string()))
+                .unwrap();
+        }
+        let fresh = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        fresh
+            .insert(
+                Arc::new(PathBuf::from("b.rs")),
+                Arc::new(SOURCE.to_string()),
+            )
+            .unwrap();
+
+        assert_eq!(shapes(&pooled, "b.rs"), shapes(&fresh, "b.rs"));
+    }
+
+    #[test]
+    fn trees_are_kept_for_files_inserted_again() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let path = Arc::new(PathBuf::from("a.rs"));
+        let tree = || engine.tree_map.get(&path).unwrap().tree().is_some();
+        engine
+            .insert(path.clone(), Arc::new(SOURCE.to_string()))
+            .unwrap();
+        assert!(!tree());
 
-        Some(())
+        engine
+            .insert(path.clone(), Arc::new(SOURCE.to_string()))
+            .unwrap();
+        assert!(tree());
     }
 }
@@ -0,0 +1,227 @@
+use std::fmt;
+
+/// Bounds on the files an [`Engine`](super::Engine) indexes, so that a generated or vendored
+/// blob cannot stall it
+///
+/// A limit of `None` is not enforced. Files beyond a limit are skipped, see [`SkipReason`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest source in bytes
+    pub max_bytes: Option<usize>,
+    /// Longest line in bytes
+    pub max_line_length: Option<usize>,
+    /// Most syntax nodes in a file
+    pub max_nodes: Option<usize>,
+    /// Whether files that look minified are skipped, see [`Limits::looks_minified`]
+    pub skip_minified: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(4 * 1024 * 1024),
+            max_line_length: Some(10_000),
+            max_nodes: Some(2_000_000),
+            skip_minified: true,
+        }
+    }
+}
+
+impl Limits {
+    /// No limits at all
+    pub fn unlimited() -> Self {
+        Self {
+            max_bytes: None,
+            max_line_length: None,
+            max_nodes: None,
+            skip_minified: false,
+        }
+    }
+
+    /// Sources below this size are never considered minified
+    const MINIFIED_MIN_BYTES: usize = 1024;
+    /// Average line length in bytes above which a source is considered minified
+    const MINIFIED_AVERAGE_LINE_LENGTH: usize = 200;
+
+    /// Whether `source` is long and made of lines far longer than code written by hand
+    pub fn looks_minified(source: &str) -> bool {
+        let lines = source.lines().count().max(1);
+        source.len() >= Self::MINIFIED_MIN_BYTES
+            && source.len() / lines > Self::MINIFIED_AVERAGE_LINE_LENGTH
+    }
+
+    /// Checks the size of a source of `bytes` bytes, which can be told before reading it
+    pub fn check_size(&self, bytes: usize) -> Result<(), SkipReason> {
+        match self.max_bytes {
+            Some(limit) if bytes > limit => Err(SkipReason::TooLarge { bytes, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks what can be told from the source alone, before parsing it
+    pub fn check_source(&self, source: &str) -> Result<(), SkipReason> {
+        self.check_size(source.len())?;
+        if source.contains('\0') {
+            return Err(SkipReason::Binary);
+        }
+        if let Some(limit) = self.max_line_length {
+            let len = source.split('\n').map(str::len).max().unwrap_or_default();
+            if len > limit {
+                return Err(SkipReason::LineTooLong { len, limit });
+            }
+        }
+        if self.skip_minified && Self::looks_minified(source) {
+            return Err(SkipReason::Minified);
+        }
+        Ok(())
+    }
+
+    /// Checks the number of syntax nodes of a parsed source
+    pub fn check_nodes(&self, nodes: usize) -> Result<(), SkipReason> {
+        match self.max_nodes {
+            Some(limit) if nodes > limit => Err(SkipReason::TooManyNodes { nodes, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why a file was not indexed
+///
+/// Whatever was indexed for the file before is removed, so that no stale duplicates are
+/// reported for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The source is larger than [`Limits::max_bytes`]
+    TooLarge { bytes: usize, limit: usize },
+    /// A line is longer than [`Limits::max_line_length`]
+    LineTooLong { len: usize, limit: usize },
+    /// The source has more syntax nodes than [`Limits::max_nodes`]
+    TooManyNodes { nodes: usize, limit: usize },
+    /// The source looks minified, see [`Limits::looks_minified`]
+    Minified,
+    /// The source contains NUL bytes, so it is most likely not text
+    Binary,
+    /// The parser gave up on the source
+    ParseFailed,
+    /// The source has syntax errors and the policy is
+    /// [`ParseErrorPolicy::SkipFile`](super::parse_error::ParseErrorPolicy::SkipFile), see
+    /// [`Engine::parse_errors_of`](super::Engine::parse_errors_of)
+    SyntaxErrors { errors: usize },
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { bytes, limit } => {
+                write!(f, "is {bytes} bytes long, more than the limit of {limit}")
+            }
+            Self::LineTooLong { len, limit } => {
+                write!(
+                    f,
+                    "has a line of {len} bytes, more than the limit of {limit}"
+                )
+            }
+            Self::TooManyNodes { nodes, limit } => {
+                write!(
+                    f,
+                    "has {nodes} syntax nodes, more than the limit of {limit}"
+                )
+            }
+            Self::Minified => write!(f, "looks minified"),
+            Self::Binary => write!(f, "looks binary"),
+            Self::ParseFailed => write!(f, "could not be parsed"),
+            Self::SyntaxErrors { errors: 1 } => write!(f, "has a syntax error"),
+            Self::SyntaxErrors { errors } => write!(f, "has {errors} syntax errors"),
+        }
+    }
+}
+
+impl std::error::Error for SkipReason {}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::{Limits, SkipReason};
+    use crate::{engine::Engine, languages::SupportedLanguage};
+
+    fn limits() -> Limits {
+        Limits {
+            max_bytes: Some(4096),
+            max_line_length: Some(100),
+            max_nodes: Some(10),
+            skip_minified: true,
+        }
+    }
+
+    #[test]
+    fn sources_beyond_a_limit_are_skipped_with_their_reason() {
+        let limits = limits();
+        assert_eq!(limits.check_source("fn f() {}\n"), Ok(()));
+        assert_eq!(
+            limits.check_source(&"x\n".repeat(2049)),
+            Err(SkipReason::TooLarge {
+                bytes: 4098,
+                limit: 4096
+            })
+        );
+        assert_eq!(
+            limits.check_source("fn f() {}\0\n"),
+            Err(SkipReason::Binary)
+        );
+        assert_eq!(
+            limits.check_source(&format!("// {}\n", "x".repeat(98))),
+            Err(SkipReason::LineTooLong {
+                len: 101,
+                limit: 100
+            })
+        );
+        assert_eq!(limits.check_nodes(10), Ok(()));
+        assert_eq!(
+            limits.check_nodes(11),
+            Err(SkipReason::TooManyNodes {
+                nodes: 11,
+                limit: 10
+            })
+        );
+    }
+
+    #[test]
+    fn long_sources_of_long_lines_look_minified() {
+        let minified = format!("{}\n", "a=1;".repeat(60)).repeat(5);
+        assert!(Limits::looks_minified(&minified));
+        assert!(!Limits::looks_minified(&"a = 1;\n".repeat(200)));
+        // Too short to tell
+        assert!(!Limits::looks_minified(&"a=1;".repeat(60)));
+
+        let limits = Limits {
+            max_line_length: None,
+            ..limits()
+        };
+        assert_eq!(limits.check_source(&minified), Err(SkipReason::Minified));
+        assert_eq!(Limits::unlimited().check_source(&minified), Ok(()));
+    }
+
+    #[test]
+    fn skipped_files_lose_what_was_indexed_for_them() {
+        let engine = Engine::builder(SupportedLanguage::from_language_id("rust").unwrap())
+            .limits(limits())
+            .build();
+        let path = Arc::new(PathBuf::from("a.rs"));
+        engine
+            .insert(path.clone(), Arc::new("fn f() {}\n".to_string()))
+            .unwrap();
+        assert!(engine.tree_map.contains_key(&path));
+
+        let source = Arc::new("fn f() {\n    a(1, 2, 3);\n}\n".to_string());
+        assert!(matches!(
+            engine.insert(path.clone(), source.clone()),
+            Err(SkipReason::TooManyNodes { limit: 10, .. })
+        ));
+        assert!(!engine.tree_map.contains_key(&path));
+
+        let skipped = engine.insert_many([(path.clone(), source)]);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, path);
+    }
+}
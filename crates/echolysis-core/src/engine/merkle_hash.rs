@@ -1,35 +1,325 @@
 use std::sync::Arc;
 
-use crate::{languages::NodeTaste, utils::hash::merge_structure_hash};
+use rustc_hash::{FxHashMap, FxHashSet};
 
-use super::{indexed_node::IndexedNode, indexed_tree::IndexedTree, Engine};
+use crate::{
+    languages::{LeafClass, NodeTaste},
+    utils::hash::merge_structure_hash_with_seed,
+};
+
+use super::{
+    indexed_node::{Id, IndexedNode},
+    indexed_tree::IndexedTree,
+    parse_error::ParseErrorPolicy,
+    sequence::Sequence,
+    Engine,
+};
+
+/// What hashing an edited file takes over from its previous version
+pub(super) struct Reuse {
+    /// Roots of the subtrees taken over unchanged, which are still in the index
+    pub(super) taken: FxHashSet<Id>,
+    /// Structure hashes of the statements of the previous version, by id
+    pub(super) statements: FxHashMap<Id, u64>,
+}
 
 impl Engine {
-    pub(super) fn merkle_hash(&self, indexed_tree: &IndexedTree) {
-        self.calculate_merkle_hash(indexed_tree.root_node());
+    /// Hashes the nodes of `indexed_tree` and adds them to the index
+    ///
+    /// With `reuse`, the subtrees taken over from the previous version of the file are neither
+    /// hashed nor indexed again.
+    pub(super) fn merkle_hash(&self, indexed_tree: &mut IndexedTree, reuse: Option<&Reuse>) {
+        let root = indexed_tree.root_node();
+        let statements = self.collect_statements(&root);
+        let mut statement_hashes: FxHashMap<_, _> = statements
+            .iter()
+            .flat_map(|(_, statements)| statements)
+            .map(|statement| (statement.id(), None))
+            .collect();
+
+        let reused = |node: &IndexedNode| {
+            let reuse = reuse?;
+            let id = node.id();
+            if !reuse.taken.contains(&id) {
+                return None;
+            }
+            let known = reuse.statements.get(&id).copied();
+            known
+                .or_else(|| self.node_hash_map.get(&id).map(|hash| *hash))
+                .or_else(|| Some(self.structure_hash(node, &mut |_, _| {})))
+        };
+        self.calculate_merkle_hash(root, &reused, |node, hash| {
+            if let Some(statement_hash) = statement_hashes.get_mut(&node.id()) {
+                *statement_hash = Some(hash);
+            }
+        });
+
+        indexed_tree.set_sequences(
+            statements
+                .into_iter()
+                .map(|(kind, statements)| {
+                    let hashes = statements
+                        .iter()
+                        .map(|statement| {
+                            // Statements inside taken over subtrees were not hashed again
+                            let id = statement.id();
+                            statement_hashes[&id]
+                                .or_else(|| reuse?.statements.get(&id).copied())
+                                .or_else(|| self.node_hash_map.get(&id).map(|hash| *hash))
+                                .unwrap_or_else(|| self.structure_hash(statement, &mut |_, _| {}))
+                        })
+                        .collect();
+                    Arc::new(Sequence::new(kind, statements, hashes))
+                })
+                .collect(),
+        );
     }
 
-    fn calculate_merkle_hash(&self, node: Arc<IndexedNode>) -> u64 {
+    /// Collects the statements of every sequence node that holds at least two of them, along
+    /// with the kind of that node
+    ///
+    /// Unless erroneous subtrees are indexed, statements with errors split the sequence, so that
+    /// the statements around them are not taken for adjacent ones.
+    fn collect_statements(&self, root: &IndexedNode) -> Vec<(&'static str, Vec<IndexedNode>)> {
+        let split_on_errors = self.parse_error_policy != ParseErrorPolicy::Index;
+        let mut res = vec![];
+        root.preorder_traverse(|node| {
+            if !self.language.indexed_node_is_sequence(node) {
+                return;
+            }
+            let mut statements = vec![];
+            for child in node.children() {
+                if split_on_errors && child.has_error() {
+                    let run = std::mem::take(&mut statements);
+                    if run.len() > 1 {
+                        res.push((node.kind(), run));
+                    }
+                } else if !child.is_leaf()
+                    && !child.is_extra_or_missing_or_error()
+                    && self.language.indexed_node_taste(&child) != NodeTaste::Ignored
+                {
+                    statements.push(child);
+                }
+            }
+            if statements.len() > 1 {
+                res.push((node.kind(), statements));
+            }
+        });
+        res
+    }
+
+    fn calculate_merkle_hash(
+        &self,
+        node: IndexedNode,
+        reused: &impl Fn(&IndexedNode) -> Option<u64>,
+        mut visit: impl FnMut(&IndexedNode, u64),
+    ) -> u64 {
+        let mut touched = vec![];
+        let index_errors = self.parse_error_policy == ParseErrorPolicy::Index;
+        let res = self.structure_hash_reusing(&node, reused, &mut |node, hash| {
+            visit(node, hash);
+            // Nodes below the reporting thresholds are indexed too, so that they can still be
+            // looked up, and are filtered out when detecting duplicates
+            if !node.is_leaf()
+                && (index_errors || !node.has_error())
+                && self.language.indexed_node_taste(node) == NodeTaste::Interesting
+            {
+                self.node_hash_map.insert(node.id(), hash);
+                self.hash_map.entry(hash).or_default().insert(node.id());
+                touched.push(hash);
+            }
+        });
+        self.mark_dirty(touched);
+        res
+    }
+
+    /// Whether `node` is hashed as a whole, like a leaf
+    ///
+    /// These are the leaves, and the literals when they are abstracted, so that a literal
+    /// counts as a single token however many nodes it has.
+    pub(super) fn is_token(&self, node: &IndexedNode) -> bool {
+        node.is_leaf()
+            || (self.language.config().abstract_literals
+                && matches!(self.language.leaf_class(node), LeafClass::Literal(_)))
+    }
+
+    /// Computes the structure hash of `node` without touching the index
+    ///
+    /// `visit` is called in postorder for every hashed node of the subtree, tokens included,
+    /// together with the hash of that node, see [`Engine::is_token`]. Ignored, extra, missing
+    /// and error nodes are not visited and contribute `0` to their parent. The hash of an inner
+    /// node starts from its kind, so that subtrees of different kinds over the same leaves hash
+    /// differently.
+    ///
+    /// The children of unordered nodes are merged and visited in the order of their hashes, so
+    /// that reordered children hash the same and their tokens line up.
+    pub(super) fn structure_hash(
+        &self,
+        node: &IndexedNode,
+        visit: &mut impl FnMut(&IndexedNode, u64),
+    ) -> u64 {
+        self.structure_hash_reusing(node, &|_| None, visit)
+    }
+
+    /// Computes the structure hash like [`Engine::structure_hash`], taking the hash of a
+    /// subtree from `reused` instead of hashing it when it knows it
+    ///
+    /// Subtrees whose hash is reused are not visited.
+    fn structure_hash_reusing(
+        &self,
+        node: &IndexedNode,
+        reused: &impl Fn(&IndexedNode) -> Option<u64>,
+        visit: &mut impl FnMut(&IndexedNode, u64),
+    ) -> u64 {
         if node.is_extra_or_missing_or_error()
-            || self.language.indexed_node_taste(&node) == NodeTaste::Ignored
+            || self.language.indexed_node_taste(node) == NodeTaste::Ignored
         {
             return 0;
         }
-        if node.children().is_empty() {
-            return self.language.simple_hash_indexed_node(&node);
+        if let Some(hash) = reused(node) {
+            return hash;
         }
-        let mut combined_hash: u64 = 0;
-        for child in node.children() {
-            combined_hash =
-                merge_structure_hash(combined_hash, self.calculate_merkle_hash(child.clone()));
+        if self.is_token(node) {
+            let hash = self.language.simple_hash_indexed_node(node);
+            visit(node, hash);
+            return hash;
         }
-        if self.language.indexed_node_taste(&node) == NodeTaste::Interesting
-            && self.language.indexed_node_cognitive_complexity(&node)
-                >= self.language.complexity_threshold()
-        {
-            self.node_hash_map.insert(node.id(), combined_hash);
-            self.hash_map.entry(combined_hash).or_default().insert(node);
+        let seed = self.language.config().seed;
+        let mut combined_hash = node.kind_id() as u64;
+        if self.language.indexed_node_is_unordered(node) {
+            let mut children: Vec<_> = node
+                .children()
+                .map(|child| {
+                    let mut visits = vec![];
+                    let mut buffer = |node: &IndexedNode, hash| visits.push((node.clone(), hash));
+                    // A trait object keeps the nested calls from instantiating ever new closures
+                    let mut buffer: &mut dyn FnMut(&IndexedNode, u64) = &mut buffer;
+                    let hash = self.structure_hash_reusing(&child, reused, &mut buffer);
+                    (hash, visits)
+                })
+                .collect();
+            children.sort_by_key(|(hash, _)| *hash);
+            for (hash, visits) in children {
+                for (node, hash) in visits {
+                    visit(&node, hash);
+                }
+                combined_hash = merge_structure_hash_with_seed(seed, combined_hash, hash);
+            }
+        } else {
+            for child in node.children() {
+                combined_hash = merge_structure_hash_with_seed(
+                    seed,
+                    combined_hash,
+                    self.structure_hash_reusing(&child, reused, visit),
+                );
+            }
         }
+        visit(node, combined_hash);
         combined_hash
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::Engine,
+        languages::{LanguageConfig, Normalization, SupportedLanguage},
+    };
+
+    const SOURCE: &str = "fn f(items: &[u32]) -> u32 {\n    items.iter().sum()\n}\n";
+
+    /// The source with its variables renamed
+    const RENAMED: &str = "fn f(values: &[u32]) -> u32 {\n    values.iter().sum()\n}\n";
+
+    /// The renamed source with other function, method and type names
+    const ABSTRACTED: &str = "fn g(values: &[u64]) -> u64 {\n    values.iter().count()\n}\n";
+
+    fn root_hash(config: LanguageConfig, source: &str) -> u64 {
+        let language = SupportedLanguage::from_language_id_with_config("rust", config).unwrap();
+        let engine = Engine::new(language);
+        let path = Arc::new(PathBuf::from("a.rs"));
+        engine
+            .insert(path.clone(), Arc::new(source.to_string()))
+            .unwrap();
+        let root = engine.tree_map.get(&path).unwrap().root_node();
+        engine.structure_hash(&root, &mut |_, _| {})
+    }
+
+    fn seeded(seed: u64) -> u64 {
+        let config = LanguageConfig {
+            seed,
+            ..LanguageConfig::default()
+        };
+        root_hash(config, SOURCE)
+    }
+
+    /// Whether the sources hash alike, with literals abstracted or not
+    fn alike(abstract_literals: bool, a: &str, b: &str) -> bool {
+        let config = LanguageConfig {
+            abstract_literals,
+            ..LanguageConfig::default()
+        };
+        root_hash(config.clone(), a) == root_hash(config, b)
+    }
+
+    /// Which of the variants hash like the source at `normalization`
+    fn matches(normalization: Normalization) -> [bool; 2] {
+        let config = LanguageConfig {
+            normalization,
+            ..LanguageConfig::default()
+        };
+        let hash = root_hash(config.clone(), SOURCE);
+        [RENAMED, ABSTRACTED].map(|variant| root_hash(config.clone(), variant) == hash)
+    }
+
+    #[test]
+    fn engines_with_the_same_seed_agree() {
+        assert_eq!(seeded(1), seeded(1));
+        assert_ne!(seeded(1), seeded(2));
+    }
+
+    #[test]
+    fn each_normalization_level_normalizes_more_names() {
+        assert_eq!(matches(Normalization::Exact), [false, false]);
+        assert_eq!(matches(Normalization::Renamed), [true, false]);
+        assert_eq!(matches(Normalization::Abstracted), [true, true]);
+    }
+
+    #[test]
+    fn abstracted_literals_hash_by_their_kind() {
+        let source = r#"fn f() { send("a\n", 'a', 1, true) }"#;
+        let same_kinds = r#"fn f() { send("b", 'b', 2.5, false) }"#;
+        assert!(!alike(false, source, same_kinds));
+        assert!(alike(true, source, same_kinds));
+
+        for other_kinds in [
+            r#"fn f() { send('a', 'a', 1, true) }"#,
+            r#"fn f() { send("a\n", 'a', "1", true) }"#,
+            r#"fn f() { send("a\n", 'a', 1, 1) }"#,
+        ] {
+            assert!(!alike(true, source, other_kinds));
+        }
+    }
+
+    #[test]
+    fn reordered_fields_and_imports_hash_alike() {
+        let hash = |source: &str| root_hash(LanguageConfig::default(), source);
+        assert_eq!(
+            hash("struct P { x: u32, y: String }"),
+            hash("struct P { y: String, x: u32 }")
+        );
+        assert_eq!(
+            hash("fn f() -> P { P { x: 1, y: s() } }"),
+            hash("fn f() -> P { P { y: s(), x: 1 } }")
+        );
+        assert_eq!(
+            hash("use std::{fs, io::Read, path::Path};"),
+            hash("use std::{path::Path, fs, io::Read};")
+        );
+        // Arguments and statements keep their order
+        assert_ne!(hash("fn f() { g(1, s()) }"), hash("fn f() { g(s(), 1) }"));
+        assert_ne!(hash("fn f() { a(); b(); }"), hash("fn f() { b(); a(); }"));
+    }
+}
@@ -1,34 +1,114 @@
+pub mod builder;
+pub mod cancel;
+pub mod delta;
+pub mod duplicate_group;
+pub mod find;
+pub mod fragment;
 pub mod indexed_node;
 pub mod indexed_tree;
+pub mod limits;
+pub mod parse_error;
+pub mod ranking;
+pub mod similarity;
 
+mod cache;
 mod detect;
 mod insert;
 mod merkle_hash;
+mod near_miss;
+mod pool;
 mod remove;
+mod renaming;
+mod sequence;
+mod verify;
 
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+};
 
+use builder::EngineBuilder;
+use cache::Cache;
 use dashmap::DashMap;
-use indexed_node::{Id, IndexedNode};
-use indexed_tree::IndexedTree;
+use duplicate_group::DuplicateGroup;
+use indexed_node::{Arena, Id, IndexedNode};
+use indexed_tree::{IndexedTree, Scratch};
+use limits::Limits;
+use parse_error::{ParseError, ParseErrorPolicy};
+use pool::Pool;
+use rayon::ThreadPool;
 use rustc_hash::{FxBuildHasher, FxHashSet};
+use sequence::{Seed, Sequence};
+use tree_sitter::Parser;
 
 use crate::languages::SupportedLanguage;
 
 pub struct Engine {
     language: SupportedLanguage,
+    verify: bool,
+    consistent_renaming: bool,
+    limits: Limits,
+    parse_error_policy: ParseErrorPolicy,
+    cache: Option<Cache>,
     tree_map: DashMap<Arc<PathBuf>, IndexedTree, ahash::RandomState>,
-    hash_map: DashMap<u64, FxHashSet<Arc<IndexedNode>>, FxBuildHasher>,
+    /// The nodes of every file, by file id, which the ids in `hash_map` are resolved with
+    files: DashMap<u64, Arc<Arena>, FxBuildHasher>,
+    hash_map: DashMap<u64, FxHashSet<Id>, FxBuildHasher>,
     node_hash_map: DashMap<Id, u64, FxBuildHasher>,
+    /// Parse errors of the files that did not parse cleanly, skipped ones included
+    parse_errors: DashMap<Arc<PathBuf>, Arc<[ParseError]>, ahash::RandomState>,
+    next_file_id: AtomicU64,
+    /// Where every pair of adjacent statements occurs, by the index of the first one
+    seeds: DashMap<Seed, Vec<(Arc<Sequence>, usize)>, FxBuildHasher>,
+    /// Duplicate groups by bucket hash, see [`Engine::update_duplicates`]
+    groups: DashMap<u64, Vec<DuplicateGroup>, FxBuildHasher>,
+    /// Groups of duplicated runs of statements by seed, see [`Engine::update_duplicates`]
+    sequence_groups: DashMap<Seed, Vec<DuplicateGroup>, FxBuildHasher>,
+    /// Buckets touched since the last update of `groups`
+    dirty: Mutex<FxHashSet<u64>>,
+    /// Seeds touched since the last update of `sequence_groups`
+    dirty_seeds: Mutex<FxHashSet<Seed>>,
+    /// Whether `dirty` and `dirty_seeds` are tracked, which starts with the first update
+    tracking: AtomicBool,
+    parsers: Pool<Parser>,
+    scratches: Pool<Scratch>,
+    /// Where parallel work runs, the global pool if unset
+    thread_pool: Option<Arc<ThreadPool>>,
 }
 
 impl Engine {
     pub fn new(language: SupportedLanguage) -> Self {
-        Self {
-            language,
-            tree_map: DashMap::with_hasher(ahash::RandomState::default()),
-            hash_map: DashMap::with_hasher(FxBuildHasher),
-            node_hash_map: DashMap::with_hasher(FxBuildHasher),
+        Self::builder(language).build()
+    }
+
+    pub fn builder(language: SupportedLanguage) -> EngineBuilder {
+        EngineBuilder::new(language)
+    }
+
+    /// The bounds on the files this engine indexes
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// The current node with `id`, if its file is still indexed and it is still there
+    pub(super) fn node(&self, id: Id) -> Option<IndexedNode> {
+        IndexedNode::with_id(self.files.get(&id.file())?.value(), id)
+    }
+
+    /// The current nodes with `ids`, leaving out the ones that are gone
+    pub(super) fn nodes<'a>(&self, ids: impl IntoIterator<Item = &'a Id>) -> Vec<IndexedNode> {
+        ids.into_iter().filter_map(|&id| self.node(id)).collect()
+    }
+
+    /// Runs `op` in the thread pool of the engine, so that the parallel iterators it uses
+    /// run there too
+    fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        match &self.thread_pool {
+            Some(thread_pool) => thread_pool.install(op),
+            None => op(),
         }
     }
 }
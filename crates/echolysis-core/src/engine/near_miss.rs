@@ -0,0 +1,428 @@
+use std::sync::OnceLock;
+
+use crate::utils::hash::merge_structure_hash;
+
+use super::{
+    cancel::{CancellationToken, Outcome},
+    duplicate_group::{DuplicateGroup, Origin},
+    fragment::Fragment,
+    indexed_node::IndexedNode,
+    ranking::Ranking,
+    similarity::leaf_similarity,
+    Engine,
+};
+
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// A node taken from a hash bucket, standing in for every node of that bucket
+struct Candidate {
+    hash: u64,
+    node: IndexedNode,
+    /// Sorted hashes of the tokens of `node`
+    fingerprint: Vec<u64>,
+    /// Tokens of `node` in source order, only collected once a comparison needs them
+    leaves: OnceLock<Vec<(IndexedNode, u64)>>,
+}
+
+impl Candidate {
+    fn leaves(&self, engine: &Engine) -> &[(IndexedNode, u64)] {
+        self.leaves
+            .get_or_init(|| engine.hashed_leaves(std::slice::from_ref(&self.node)))
+    }
+}
+
+impl Engine {
+    /// Detect near-miss (Type-3) duplicate code blocks in the parsed source files.
+    ///
+    /// Two nodes are considered near-miss duplicates when they are of the same kind and their
+    /// tokens are at least `similarity` similar, measured like
+    /// [`Similarity::score`](super::similarity::Similarity::score), so fragments that were
+    /// slightly edited after being copied are still found. Members of a group are similar to
+    /// each other rather than through a chain of members, so the score of every group reaches
+    /// `similarity`. Groups consisting solely of identical nodes are left to
+    /// [`Engine::detect_duplicates`].
+    ///
+    /// # Arguments
+    /// * `similarity` - Minimum similarity ratio in `(0.0, 1.0]` for two nodes to be grouped
+    /// * `ranking` - How groups are ordered, most valuable first
+    /// * `limitation` - Optional maximum number of duplicate groups to return
+    ///
+    /// # Returns
+    /// A vector of duplicate groups, where each group contains similar code blocks
+    pub fn detect_near_duplicates(
+        &self,
+        similarity: f64,
+        ranking: Ranking,
+        limitation: Option<usize>,
+    ) -> Vec<DuplicateGroup> {
+        self.detect_near_duplicates_cancellable(
+            similarity,
+            ranking,
+            limitation,
+            &CancellationToken::new(),
+        )
+        .into_inner()
+    }
+
+    /// Detects near-miss duplicates like [`Engine::detect_near_duplicates`] until `cancel` is
+    /// cancelled
+    ///
+    /// The groups found by then are returned as a partial outcome.
+    pub fn detect_near_duplicates_cancellable(
+        &self,
+        similarity: f64,
+        ranking: Ranking,
+        limitation: Option<usize>,
+        cancel: &CancellationToken,
+    ) -> Outcome<Vec<DuplicateGroup>> {
+        self.install(|| {
+            let similarity = similarity.clamp(f64::EPSILON, 1.0);
+            let coverage = self.collect_coverage(cancel);
+
+            let mut candidates: Vec<_> = self
+                .hash_map
+                .par_iter()
+                .filter(|entry| {
+                    !cancel.is_cancelled()
+                        && self.meets_thresholds(entry.value())
+                        && !Self::is_covered(entry.value().iter(), &coverage)
+                })
+                .filter_map(|entry| {
+                    let node = self.nodes(entry.value()).into_iter().min_by(|lhs, rhs| {
+                        (lhs.path(), lhs.byte_range()).cmp(&(rhs.path(), rhs.byte_range()))
+                    })?;
+                    Some(Candidate {
+                        hash: *entry.key(),
+                        fingerprint: self.fingerprint(&node),
+                        leaves: OnceLock::new(),
+                        node,
+                    })
+                })
+                .collect();
+            // A fixed order of candidates keeps the clustering below deterministic
+            candidates.sort_by(|lhs, rhs| {
+                (lhs.node.path(), lhs.node.byte_range())
+                    .cmp(&(rhs.node.path(), rhs.node.byte_range()))
+            });
+
+            let mut clusters = self.cluster_candidates(&candidates, similarity, cancel);
+            // Larger fragments first, so that fragments nested inside them can be suppressed
+            clusters.sort_by_key(|cluster| {
+                std::cmp::Reverse(
+                    cluster
+                        .iter()
+                        .map(|&i| candidates[i].fingerprint.len())
+                        .max(),
+                )
+            });
+
+            let mut covered = FxHashSet::default();
+            let mut groups = vec![];
+            for cluster in clusters {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let group: Vec<_> = cluster
+                    .iter()
+                    .flat_map(|&i| {
+                        let candidate = &candidates[i];
+                        self.hash_map
+                            .get(&candidate.hash)
+                            .map(|nodes| {
+                                self.nodes(nodes.iter())
+                                    .into_iter()
+                                    .filter(|node| {
+                                        !self.verify
+                                            || self.structurally_equal(node, &candidate.node)
+                                    })
+                                    .collect::<Vec<_>>()
+                            })
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                let group = self.similar_to_first(group, similarity);
+                if group.len() < 2 || group.iter().all(|node| covered.contains(&node.id())) {
+                    continue;
+                }
+                for node in &group {
+                    for child in IndexedNode::all_children(node.clone()) {
+                        covered.insert(child.id());
+                    }
+                }
+                let mut hashes: Vec<_> = cluster.iter().map(|&i| candidates[i].hash).collect();
+                hashes.sort_unstable();
+                let hash = hashes.into_iter().fold(0, merge_structure_hash);
+                let kind = group[0].kind();
+                groups.push(self.duplicate_group(
+                    hash,
+                    Origin::NearMiss,
+                    kind,
+                    group.into_iter().map(Fragment::from).collect(),
+                ));
+            }
+            Outcome::new(self.rank(groups, ranking, limitation), cancel)
+        })
+    }
+
+    /// Keeps the nodes of `group` that are at least `similarity` similar to the first one in
+    /// location order, which the score of the group is measured against
+    ///
+    /// Nodes sharing a hash may still differ in their text, so this is not implied by the
+    /// similarity of the candidates standing for them.
+    fn similar_to_first(&self, mut group: Vec<IndexedNode>, similarity: f64) -> Vec<IndexedNode> {
+        group.sort_by(|lhs, rhs| {
+            (lhs.path(), lhs.byte_range()).cmp(&(rhs.path(), rhs.byte_range()))
+        });
+        let Some(first) = group.first() else {
+            return group;
+        };
+        let reference = self.hashed_leaves(std::slice::from_ref(first));
+        group.retain(|node| {
+            leaf_similarity(&reference, &self.hashed_leaves(std::slice::from_ref(node)))
+                >= similarity
+        });
+        group
+    }
+
+    /// Collects the sorted hashes of the tokens of `node`
+    fn fingerprint(&self, node: &IndexedNode) -> Vec<u64> {
+        let mut fingerprint = vec![];
+        self.structure_hash(node, &mut |node, hash| {
+            if self.is_token(node) {
+                fingerprint.push(hash);
+            }
+        });
+        fingerprint.sort_unstable();
+        fingerprint
+    }
+
+    /// Clusters candidates whose similarity to each other reaches the threshold, returning
+    /// clusters of candidate indices that span at least two different hashes
+    fn cluster_candidates(
+        &self,
+        candidates: &[Candidate],
+        similarity: f64,
+        cancel: &CancellationToken,
+    ) -> Vec<Vec<usize>> {
+        // Only nodes of the same kind are compared, ordered by fingerprint size so that the
+        // comparison can stop as soon as the size ratio alone rules out a match. Aligning the
+        // tokens is costly, so it is left for pairs whose multisets of tokens overlap enough,
+        // which bounds their similarity from above
+        let mut by_kind: FxHashMap<&str, Vec<usize>> = FxHashMap::default();
+        for (i, candidate) in candidates.iter().enumerate() {
+            by_kind.entry(candidate.node.kind()).or_default().push(i);
+        }
+        let buckets: Vec<_> = by_kind
+            .into_values()
+            .map(|mut bucket| {
+                bucket.sort_by_key(|&i| candidates[i].fingerprint.len());
+                bucket
+            })
+            .collect();
+
+        let edges: Vec<(usize, usize)> = buckets
+            .par_iter()
+            .flat_map_iter(|bucket| {
+                (0..bucket.len())
+                    .take_while(move |_| !cancel.is_cancelled())
+                    .flat_map(move |x| {
+                        let lhs = &candidates[bucket[x]].fingerprint;
+                        let max_len = lhs.len() as f64 * (2.0 - similarity) / similarity;
+                        bucket[x + 1..]
+                            .iter()
+                            .take_while(move |&&y| {
+                                candidates[y].fingerprint.len() as f64 <= max_len
+                            })
+                            .filter(move |&&y| {
+                                let (lhs, rhs) = (&candidates[bucket[x]], &candidates[y]);
+                                !is_nested(&lhs.node, &rhs.node)
+                                    && dice_coefficient(&lhs.fingerprint, &rhs.fingerprint)
+                                        >= similarity
+                                    && leaf_similarity(lhs.leaves(self), rhs.leaves(self))
+                                        >= similarity
+                            })
+                            .map(move |&y| (bucket[x], y))
+                    })
+            })
+            .collect();
+
+        // Complete linkage: a candidate joins the first cluster of its neighbours that it is
+        // similar to as a whole, so that chains of similar candidates never link dissimilar ones
+        let mut neighbours: Vec<Vec<usize>> = vec![vec![]; candidates.len()];
+        for (lhs, rhs) in edges {
+            neighbours[lhs.max(rhs)].push(lhs.min(rhs));
+        }
+        let mut clusters: Vec<Vec<usize>> = vec![];
+        let mut cluster_of: Vec<Option<usize>> = vec![None; candidates.len()];
+        for (i, neighbours) in neighbours.iter_mut().enumerate() {
+            neighbours.sort_unstable();
+            let joined = neighbours.iter().filter_map(|&j| cluster_of[j]).find(|&c| {
+                clusters[c]
+                    .iter()
+                    .all(|j| neighbours.binary_search(j).is_ok())
+            });
+            let c = joined.unwrap_or_else(|| {
+                clusters.push(vec![]);
+                clusters.len() - 1
+            });
+            clusters[c].push(i);
+            cluster_of[i] = Some(c);
+        }
+        clusters
+            .into_iter()
+            .filter(|cluster| cluster.len() > 1)
+            .collect()
+    }
+}
+
+/// Whether one of the nodes lies inside the other, like the links of a call chain
+fn is_nested(lhs: &IndexedNode, rhs: &IndexedNode) -> bool {
+    let ((lhs_start, lhs_end), (rhs_start, rhs_end)) = (lhs.byte_range(), rhs.byte_range());
+    lhs.path() == rhs.path()
+        && ((lhs_start <= rhs_start && rhs_end <= lhs_end)
+            || (rhs_start <= lhs_start && lhs_end <= rhs_end))
+}
+
+/// Computes the Sørensen–Dice coefficient of two sorted multisets
+fn dice_coefficient(lhs: &[u64], rhs: &[u64]) -> f64 {
+    if lhs.is_empty() && rhs.is_empty() {
+        return 1.0;
+    }
+    let (mut i, mut j, mut common) = (0, 0, 0);
+    while i < lhs.len() && j < rhs.len() {
+        match lhs[i].cmp(&rhs[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                common += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    (2 * common) as f64 / (lhs.len() + rhs.len()) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::dice_coefficient;
+    use crate::{
+        engine::{duplicate_group::Origin, ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const ORIGINAL: &str = r#"
+fn process(items: &[u32], limit: u32) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > limit {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    while total > 1000 {
+        total /= 2;
+    }
+    total
+}
+"#;
+
+    fn engine(sources: &[(&str, String)]) -> Engine {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        for (path, source) in sources {
+            engine
+                .insert(Arc::new(PathBuf::from(path)), Arc::new(source.clone()))
+                .unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn edited_copy_is_a_near_duplicate() {
+        let copy = ORIGINAL.replace("*item > limit", "*item >= limit");
+        let engine = engine(&[("original.rs", ORIGINAL.to_string()), ("copy.rs", copy)]);
+
+        let groups = engine.detect_near_duplicates(0.75, Ranking::default(), None);
+        let function = groups
+            .iter()
+            .find(|group| group.kind() == "function_item")
+            .expect("the edited function is a near-miss duplicate");
+        assert_eq!(function.origin(), Origin::NearMiss);
+        let paths: Vec<_> = function
+            .fragments()
+            .iter()
+            .map(|fragment| fragment.path())
+            .collect();
+        assert_eq!(
+            paths,
+            [PathBuf::from("copy.rs"), PathBuf::from("original.rs")]
+        );
+    }
+
+    #[test]
+    fn identical_copies_are_left_to_exact_detection() {
+        let engine = engine(&[
+            ("original.rs", ORIGINAL.to_string()),
+            ("copy.rs", ORIGINAL.to_string()),
+        ]);
+
+        let groups = engine.detect_near_duplicates(0.8, Ranking::default(), None);
+        assert!(groups.iter().all(|group| group.kind() != "function_item"));
+    }
+
+    #[test]
+    fn dice_coefficient_counts_shared_hashes_as_multisets() {
+        assert_eq!(dice_coefficient(&[], &[]), 1.0);
+        assert_eq!(dice_coefficient(&[1, 2, 2, 3], &[2, 2, 4]), 4.0 / 7.0);
+        assert_eq!(dice_coefficient(&[1], &[2]), 0.0);
+    }
+
+    #[test]
+    fn added_log_line_keeps_the_copy_within_the_reported_similarity() {
+        let copy = ORIGINAL.replace(
+            "let mut total = 0;",
+            "let mut total = 0;\n    println!(\"processing {} items\", items.len());",
+        );
+        let engine = engine(&[("original.rs", ORIGINAL.to_string()), ("copy.rs", copy)]);
+
+        let groups = engine.detect_near_duplicates(0.9, Ranking::default(), None);
+        let function = groups
+            .iter()
+            .find(|group| group.kind() == "function_item")
+            .expect("the copied function is a near-miss duplicate");
+        assert_eq!(function.len(), 2);
+        for group in &groups {
+            assert!(group.similarity().score() >= 0.9);
+        }
+    }
+
+    #[test]
+    fn copies_are_not_chained_beyond_the_similarity() {
+        let logged = ORIGINAL.replace(
+            "let mut total = 0;",
+            "let mut total = 0;\n    log(1, 2, 3);",
+        );
+        let reported = logged.replace("log(1, 2, 3);", "log(1, 2, 3);\n    report(4, 5, 6);");
+        let engine = engine(&[
+            ("a.rs", ORIGINAL.to_string()),
+            ("b.rs", logged),
+            ("c.rs", reported),
+        ]);
+
+        let groups = engine.detect_near_duplicates(0.9, Ranking::default(), None);
+        let function = groups
+            .iter()
+            .find(|group| group.kind() == "function_item")
+            .expect("the logged copy is a near-miss duplicate");
+        assert_eq!(function.len(), 2);
+        for group in &groups {
+            assert!(group.similarity().score() >= 0.9);
+        }
+    }
+}
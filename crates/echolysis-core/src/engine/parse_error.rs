@@ -0,0 +1,186 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use super::{indexed_node::IndexedNode, Engine};
+
+/// What an [`Engine`] does with files that did not parse cleanly
+///
+/// Error and missing nodes never contribute to structure hashes, so a subtree around a syntax
+/// error hashes like the same code with the erroneous part left out. That is rarely a duplicate
+/// worth reporting and makes groups come and go while code is typed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseErrorPolicy {
+    /// Indexes the file, leaving out every node that has an error in its subtree
+    ///
+    /// Sequences of statements are split around erroneous statements.
+    #[default]
+    SkipSubtrees,
+    /// Skips the whole file, see [`SkipReason::SyntaxErrors`](super::limits::SkipReason)
+    SkipFile,
+    /// Indexes the file as if the erroneous parts were not there
+    Index,
+}
+
+/// A place where the parser could not make sense of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Kind of the node the parser inserted to recover, `None` for text it had to leave out
+    pub missing: Option<&'static str>,
+    pub byte_range: (usize, usize),
+    pub position_range: (tree_sitter::Point, tree_sitter::Point),
+}
+
+impl ParseError {
+    fn new(node: &IndexedNode) -> Self {
+        Self {
+            missing: node.is_missing().then(|| node.kind()),
+            byte_range: node.byte_range(),
+            position_range: node.position_range(),
+        }
+    }
+
+    /// The outermost error nodes and the missing nodes below `node`, in source order
+    pub(super) fn collect(node: &IndexedNode, res: &mut Vec<ParseError>) {
+        if node.is_error() || node.is_missing() {
+            res.push(Self::new(node));
+        } else if node.has_error() {
+            for child in node.children() {
+                Self::collect(&child, res);
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (start, _) = self.position_range;
+        match self.missing {
+            Some(kind) => write!(f, "missing `{kind}`")?,
+            None => write!(f, "syntax error")?,
+        }
+        write!(f, " at {}:{}", start.row + 1, start.column + 1)
+    }
+}
+
+impl Engine {
+    /// The parse errors of every indexed or skipped file that did not parse cleanly
+    pub fn parse_errors(&self) -> Vec<(Arc<PathBuf>, Arc<[ParseError]>)> {
+        self.parse_errors
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// The parse errors of the file at `path`, empty if it parsed cleanly or is unknown
+    pub fn parse_errors_of(&self, path: &PathBuf) -> Arc<[ParseError]> {
+        self.parse_errors
+            .get(path)
+            .map(|errors| errors.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::ParseErrorPolicy;
+    use crate::{
+        engine::{limits::SkipReason, ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const FUNCTION: &str = r#"
+fn process(items: &[u32], limit: u32) -> u32 {
+    let mut total = 0;
+    for item in items {
+        if *item > limit {
+            total += item * 2;
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    total
+}
+"#;
+
+    /// The function with a statement the parser cannot make sense of
+    fn broken() -> String {
+        FUNCTION.replace("    total\n}", "    let = ;\n    total\n}")
+    }
+
+    fn engine(policy: ParseErrorPolicy) -> Engine {
+        Engine::builder(SupportedLanguage::from_language_id("rust").unwrap())
+            .parse_error_policy(policy)
+            .build()
+    }
+
+    fn insert(engine: &Engine, path: &str, source: String) -> Result<(), SkipReason> {
+        engine.insert(Arc::new(PathBuf::from(path)), Arc::new(source))
+    }
+
+    /// Kinds of the detected groups
+    fn kinds(engine: &Engine) -> Vec<&'static str> {
+        let mut kinds: Vec<_> = engine
+            .detect_duplicates(Ranking::default(), None)
+            .iter()
+            .map(|group| group.kind())
+            .collect();
+        kinds.sort();
+        kinds
+    }
+
+    #[test]
+    fn erroneous_subtrees_are_left_out_by_default() {
+        let engine = engine(ParseErrorPolicy::default());
+        insert(&engine, "a.rs", FUNCTION.to_string()).unwrap();
+        insert(&engine, "b.rs", broken()).unwrap();
+
+        // The loop is intact in both copies, the function is not
+        assert_eq!(kinds(&engine), ["for_expression"]);
+        let errors = engine.parse_errors_of(&PathBuf::from("b.rs"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].position_range.0.row, 12);
+        assert!(engine.parse_errors_of(&PathBuf::from("a.rs")).is_empty());
+    }
+
+    #[test]
+    fn erroneous_files_can_be_skipped_as_a_whole() {
+        let engine = engine(ParseErrorPolicy::SkipFile);
+        insert(&engine, "a.rs", FUNCTION.to_string()).unwrap();
+        insert(&engine, "b.rs", FUNCTION.to_string()).unwrap();
+        assert_eq!(
+            insert(&engine, "b.rs", broken()),
+            Err(SkipReason::SyntaxErrors { errors: 1 })
+        );
+
+        assert!(kinds(&engine).is_empty());
+        assert_eq!(engine.parse_errors().len(), 1);
+
+        // Fixing the file clears its errors
+        insert(&engine, "b.rs", FUNCTION.to_string()).unwrap();
+        assert!(engine.parse_errors().is_empty());
+        assert_eq!(kinds(&engine), ["function_item"]);
+    }
+
+    #[test]
+    fn erroneous_subtrees_can_be_indexed() {
+        let engine = engine(ParseErrorPolicy::Index);
+        insert(&engine, "a.rs", broken()).unwrap();
+        insert(&engine, "b.rs", broken()).unwrap();
+
+        assert_eq!(kinds(&engine), ["function_item"]);
+        assert_eq!(engine.parse_errors().len(), 2);
+    }
+
+    #[test]
+    fn missing_nodes_name_their_kind() {
+        let engine = engine(ParseErrorPolicy::default());
+        insert(&engine, "a.rs", "fn f() {\n    let x = 1\n}\n".to_string()).unwrap();
+
+        let errors = engine.parse_errors_of(&PathBuf::from("a.rs"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "missing `;` at 2:14");
+    }
+}
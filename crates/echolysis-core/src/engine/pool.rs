@@ -0,0 +1,107 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// Objects that are costly to set up, like parsers, kept for reuse across inserts
+///
+/// The pool grows to the number of threads that used it at the same time and never shrinks.
+pub(super) struct Pool<T> {
+    items: Mutex<Vec<T>>,
+    /// Whether objects are kept at all, otherwise every one is created for a single use
+    keep: bool,
+}
+
+impl<T> Pool<T> {
+    pub(super) fn new(keep: bool) -> Self {
+        Self {
+            items: Mutex::new(vec![]),
+            keep,
+        }
+    }
+
+    /// Takes an object out of the pool, or creates one with `create` if none is left
+    ///
+    /// The object goes back to the pool when the returned guard is dropped.
+    pub(super) fn get(&self, create: impl FnOnce() -> T) -> Pooled<'_, T> {
+        let item = self.items.lock().unwrap().pop();
+        Pooled {
+            pool: self,
+            item: Some(item.unwrap_or_else(create)),
+        }
+    }
+}
+
+/// An object taken out of a [`Pool`]
+pub(super) struct Pooled<'a, T> {
+    pool: &'a Pool<T>,
+    item: Option<T>,
+}
+
+impl<T> Deref for Pooled<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The item is only taken out on drop
+        self.item.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for Pooled<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The item is only taken out on drop
+        self.item.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for Pooled<'_, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take().filter(|_| self.pool.keep) {
+            self.pool.items.lock().unwrap().push(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pool;
+
+    #[test]
+    fn returned_items_are_reused() {
+        let pool = Pool::new(true);
+        let mut created = 0;
+        for _ in 0..3 {
+            let item = pool.get(|| {
+                created += 1;
+                created
+            });
+            assert_eq!(*item, 1);
+        }
+        assert_eq!(created, 1);
+    }
+
+    #[test]
+    fn items_in_use_are_not_handed_out_twice() {
+        let pool = Pool::new(true);
+        let first = pool.get(|| vec![1]);
+        let mut second = pool.get(|| vec![2]);
+        second.push(3);
+        assert_eq!((&*first, &*second), (&vec![1], &vec![2, 3]));
+
+        drop(second);
+        assert_eq!(*pool.get(Vec::new), [2, 3]);
+    }
+
+    #[test]
+    fn pools_that_do_not_keep_create_every_item() {
+        let pool = Pool::new(false);
+        let mut created = 0;
+        for _ in 0..3 {
+            pool.get(|| {
+                created += 1;
+                created
+            });
+        }
+        assert_eq!(created, 3);
+    }
+}
@@ -0,0 +1,156 @@
+use rayon::prelude::*;
+
+use super::{duplicate_group::DuplicateGroup, Engine};
+
+/// How duplicate groups are ordered, most valuable first
+///
+/// Every ranking scores a group by a measure of its first member multiplied by the number of
+/// members, so that frequently copied code ranks above code copied only once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Ranking {
+    /// Number of lines spanned
+    #[default]
+    Lines,
+    /// Cognitive complexity
+    Complexity,
+    /// Number of leaf tokens
+    Tokens,
+}
+
+impl Engine {
+    /// Orders groups by `ranking`, keeping the first `limitation` groups
+    ///
+    /// Groups with equal scores are ordered by the location of their first member, so the
+    /// result does not depend on hashing or thread scheduling. Groups are sorted in parallel, so
+    /// this is to be called within [`Engine::install`].
+    pub(super) fn rank(
+        &self,
+        mut groups: Vec<DuplicateGroup>,
+        ranking: Ranking,
+        limitation: Option<usize>,
+    ) -> Vec<DuplicateGroup> {
+        groups.par_sort_by(|lhs, rhs| {
+            score(rhs, ranking)
+                .total_cmp(&score(lhs, ranking))
+                .then_with(|| {
+                    let (lhs, rhs) = (&lhs.fragments()[0], &rhs.fragments()[0]);
+                    (lhs.path(), lhs.byte_range()).cmp(&(rhs.path(), rhs.byte_range()))
+                })
+        });
+        groups.truncate(limitation.unwrap_or(usize::MAX));
+        groups
+    }
+}
+
+fn score(group: &DuplicateGroup, ranking: Ranking) -> f64 {
+    let measure = match ranking {
+        Ranking::Lines => group.lines() as f64,
+        Ranking::Complexity => group.complexity(),
+        Ranking::Tokens => group.tokens() as f64,
+    };
+    measure * group.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::Ranking;
+    use crate::{
+        engine::{
+            duplicate_group::{DuplicateGroup, Origin},
+            fragment::Fragment,
+            indexed_node::IndexedNode,
+            Engine,
+        },
+        languages::SupportedLanguage,
+    };
+
+    /// The items of one file per source, which all span as many lines
+    fn items(engine: &Engine, sources: &[&str]) -> Vec<Vec<IndexedNode>> {
+        sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                let path = Arc::new(PathBuf::from(format!("file{i}.rs")));
+                engine
+                    .insert(path.clone(), Arc::new(source.to_string()))
+                    .unwrap();
+                engine
+                    .tree_map
+                    .get(&path)
+                    .unwrap()
+                    .root_node()
+                    .children()
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn group(engine: &Engine, nodes: &[&IndexedNode]) -> DuplicateGroup {
+        let fragments = nodes
+            .iter()
+            .map(|&node| Fragment::new(vec![node.clone()]))
+            .collect();
+        engine.duplicate_group(0, Origin::Node, "function_item", fragments)
+    }
+
+    #[test]
+    fn ties_are_broken_by_location() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let source = "fn f() {\n    a();\n}\nfn g() {\n    b();\n}\n";
+        let items = items(&engine, &[source, source, source]);
+        // Groups and their members come in an order that depends on nothing
+        let groups = vec![
+            group(&engine, &[&items[2][1], &items[2][0]]),
+            group(&engine, &[&items[0][1], &items[0][0]]),
+            group(&engine, &[&items[1][1], &items[1][0]]),
+        ];
+
+        let ranked = engine.rank(groups, Ranking::Lines, Some(2));
+        let locations: Vec<Vec<_>> = ranked
+            .iter()
+            .map(|group| {
+                group
+                    .fragments()
+                    .iter()
+                    .map(|fragment| (fragment.path().to_path_buf(), fragment.byte_range().0))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            [
+                [
+                    (PathBuf::from("file0.rs"), 0),
+                    (PathBuf::from("file0.rs"), 20)
+                ],
+                [
+                    (PathBuf::from("file1.rs"), 0),
+                    (PathBuf::from("file1.rs"), 20)
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_with_more_members_rank_higher() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let items = items(
+            &engine,
+            &[
+                "fn f() {\n    a();\n}\n",
+                "fn g() {\n    b();\n}\n",
+                "fn h() {\n    c();\n}\n",
+            ],
+        );
+        let pair = group(&engine, &[&items[0][0], &items[1][0]]);
+        let triple = group(&engine, &[&items[2][0], &items[1][0], &items[0][0]]);
+
+        let ranked = engine.rank(vec![pair, triple], Ranking::Lines, None);
+        assert_eq!(
+            ranked.iter().map(DuplicateGroup::len).collect::<Vec<_>>(),
+            [3, 2]
+        );
+    }
+}
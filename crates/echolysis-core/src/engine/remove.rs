@@ -2,18 +2,29 @@ use std::{path::PathBuf, sync::Arc};
 
 use rayon::prelude::*;
 
-use super::{indexed_node::IndexedNode, Engine};
+use super::{
+    indexed_node::{Id, IndexedNode},
+    Engine,
+};
 
 impl Engine {
     pub fn remove_many(&self, paths: impl IntoParallelIterator<Item = Arc<PathBuf>>) {
-        let trees_to_remove: Vec<_> = paths
-            .into_par_iter()
-            .filter_map(|path| self.tree_map.remove(&path).map(|(_, tree)| tree))
-            .collect();
+        let paths = paths.into_par_iter();
+        self.install(|| {
+            let trees_to_remove: Vec<_> = paths
+                .filter_map(|path| {
+                    self.parse_errors.remove(&path);
+                    let (_, tree) = self.tree_map.remove(&path)?;
+                    self.files.remove(&tree.root_node().id().file());
+                    Some(tree)
+                })
+                .collect();
 
-        trees_to_remove.into_par_iter().for_each(|tree| {
-            self.remove_merkle_hashes(tree.root_node());
-        });
+            trees_to_remove.into_par_iter().for_each(|tree| {
+                self.remove_merkle_hashes(tree.root_node());
+                self.unindex_sequences(tree.sequences());
+            });
+        })
     }
 
     pub fn remove_all(&self) {
@@ -23,26 +34,80 @@ impl Engine {
                 .map(|entry| entry.key().clone())
                 .collect::<Vec<_>>(),
         );
+        // Skipped files have parse errors without being indexed
+        self.parse_errors.clear();
     }
 
     pub fn remove(&self, path: Arc<PathBuf>) {
+        self.parse_errors.remove(&path);
         if let dashmap::Entry::Occupied(entry) = self.tree_map.entry(path) {
             self.remove_merkle_hashes(entry.get().root_node());
+            self.unindex_sequences(entry.get().sequences());
+            self.files.remove(&entry.get().root_node().id().file());
             entry.remove();
         }
     }
 
-    pub(super) fn remove_merkle_hashes(&self, node: Arc<IndexedNode>) {
+    pub(super) fn remove_merkle_hashes(&self, node: IndexedNode) {
+        let mut nodes = vec![];
+        node.preorder_traverse(|x| nodes.push(x.id()));
+        self.remove_ids(nodes);
+    }
+
+    /// Removes the nodes below `node` from the index, except for the subtrees starting at the
+    /// given preorder indices with the given lengths, which were taken over by a new tree
+    pub(super) fn remove_merkle_hashes_except(&self, node: IndexedNode, kept: &[(usize, usize)]) {
+        let mut nodes = vec![];
         node.preorder_traverse(|x| {
-            if let Some((_, h)) = self.node_hash_map.remove(&x.id()) {
+            let index = x.preorder_index();
+            // Kept subtrees were taken over in preorder, so they are sorted
+            let i = kept.partition_point(|&(start, _)| start <= index);
+            if i == 0 || kept[i - 1].0 + kept[i - 1].1 <= index {
+                nodes.push(x.id());
+            }
+        });
+        self.remove_ids(nodes);
+    }
+
+    fn remove_ids(&self, ids: Vec<Id>) {
+        let mut touched = vec![];
+        for id in ids {
+            if let Some((_, h)) = self.node_hash_map.remove(&id) {
+                touched.push(h);
                 if let dashmap::Entry::Occupied(mut entry) = self.hash_map.entry(h) {
                     let set = entry.get_mut();
-                    set.remove(x);
+                    set.remove(&id);
                     if set.is_empty() {
                         entry.remove();
                     }
                 }
             }
-        });
+        }
+        self.mark_dirty(touched);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{parse_error::ParseErrorPolicy, Engine},
+        languages::SupportedLanguage,
+    };
+
+    #[test]
+    fn remove_all_forgets_parse_errors_of_skipped_files() {
+        let engine = Engine::builder(SupportedLanguage::from_language_id("rust").unwrap())
+            .parse_error_policy(ParseErrorPolicy::SkipFile)
+            .build();
+        let path = Arc::new(PathBuf::from("broken.rs"));
+        assert!(engine
+            .insert(path.clone(), Arc::new("fn broken( {".to_string()))
+            .is_err());
+        assert!(!engine.parse_errors_of(&path).is_empty());
+
+        engine.remove_all();
+        assert!(engine.parse_errors().is_empty());
     }
 }
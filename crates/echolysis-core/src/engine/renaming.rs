@@ -0,0 +1,127 @@
+use std::collections::hash_map::Entry;
+
+use rustc_hash::FxHashMap;
+
+use crate::languages::LeafClass;
+
+use super::{indexed_node::IndexedNode, Engine};
+
+impl Engine {
+    /// Whether `node` is a token whose text normalization hides, like a variable name
+    ///
+    /// Literals are left out, as they are values rather than names and may differ freely.
+    fn is_renamable(&self, node: &IndexedNode) -> bool {
+        let class = self.language.leaf_class(node);
+        !matches!(class, LeafClass::Literal(_)) && self.language.config().normalizes(class)
+    }
+
+    /// The renaming that turns the identifiers of one member into those of another, given
+    /// their aligned tokens
+    ///
+    /// # Returns
+    /// The pairs of names that differ, in order of first occurrence, or `None` if the renaming
+    /// is not consistent, that is if a name maps to two names or two names map to the same one
+    pub(super) fn renaming<'a>(
+        &self,
+        pairs: impl IntoIterator<Item = (&'a IndexedNode, &'a IndexedNode)>,
+    ) -> Option<Vec<(String, String)>> {
+        let mut forward = FxHashMap::default();
+        let mut backward = FxHashMap::default();
+        let mut res = vec![];
+        for (lhs, rhs) in pairs {
+            if !self.is_renamable(lhs) || !self.is_renamable(rhs) {
+                continue;
+            }
+            let (lhs, rhs) = (lhs.text(), rhs.text());
+            match forward.entry(lhs) {
+                Entry::Occupied(entry) => {
+                    if *entry.get() != rhs {
+                        return None;
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(rhs);
+                    if backward.insert(rhs, lhs).is_some() {
+                        return None;
+                    }
+                    if lhs != rhs {
+                        res.push((lhs.to_string(), rhs.to_string()));
+                    }
+                }
+            }
+        }
+        Some(res)
+    }
+
+    /// Whether the identifiers of `lhs` are consistently renamed in `rhs`, which are assumed
+    /// to share a structure hash
+    pub(super) fn consistently_renamed(&self, lhs: &[IndexedNode], rhs: &[IndexedNode]) -> bool {
+        let (lhs, rhs) = (self.hashed_leaves(lhs), self.hashed_leaves(rhs));
+        lhs.len() == rhs.len()
+            && self
+                .renaming(lhs.iter().zip(&rhs).map(|((lhs, _), (rhs, _))| (lhs, rhs)))
+                .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{indexed_node::IndexedNode, Engine},
+        languages::SupportedLanguage,
+    };
+
+    /// The sums of `source`, ordered by location
+    fn sums(engine: &Engine, source: &str) -> Vec<IndexedNode> {
+        let path = Arc::new(PathBuf::from("a.rs"));
+        engine
+            .insert(path.clone(), Arc::new(source.to_string()))
+            .unwrap();
+        let root = engine.tree_map.get(&path).unwrap().root_node();
+        let mut sums: Vec<_> = IndexedNode::all_children(root)
+            .into_iter()
+            .filter(|node| node.kind() == "binary_expression" && node.text().contains('+'))
+            .collect();
+        sums.sort_by_key(|node| node.byte_range());
+        sums
+    }
+
+    #[test]
+    fn inconsistently_renamed_items_are_split_when_required() {
+        let source = "fn f() {\n    a + b * a;\n    x + y * z;\n    c + d * c;\n}\n";
+        for (consistent_renaming, expected) in [
+            (false, vec![vec!["a + b * a", "x + y * z", "c + d * c"]]),
+            (
+                true,
+                vec![vec!["a + b * a", "c + d * c"], vec!["x + y * z"]],
+            ),
+        ] {
+            let engine = Engine::builder(SupportedLanguage::from_language_id("rust").unwrap())
+                .consistent_renaming(consistent_renaming)
+                .build();
+            let sums = sums(&engine, source);
+
+            let classes = engine.verified_classes(sums, std::slice::from_ref);
+            let texts: Vec<Vec<_>> = classes
+                .iter()
+                .map(|class| class.iter().map(|node| node.text()).collect())
+                .collect();
+            assert_eq!(texts, expected);
+        }
+    }
+
+    #[test]
+    fn names_may_not_merge_or_split() {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        let sums = sums(
+            &engine,
+            "fn f() {\n    a + b * a;\n    x + y * x;\n    a + a * a;\n}\n",
+        );
+
+        assert!(engine.consistently_renamed(&sums[..1], &sums[1..2]));
+        assert!(!engine.consistently_renamed(&sums[..1], &sums[2..]));
+        assert!(!engine.consistently_renamed(&sums[2..], &sums[..1]));
+    }
+}
@@ -0,0 +1,360 @@
+use std::sync::Arc;
+
+use crate::utils::hash::merge_structure_hash;
+
+use super::{
+    cancel::{CancellationToken, Outcome},
+    duplicate_group::{DuplicateGroup, Origin},
+    fragment::Fragment,
+    indexed_node::{Id, IndexedNode},
+    ranking::Ranking,
+    verify::class_hash,
+    Engine,
+};
+
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// The statements of a sequence node, such as a block, along with their structure hashes
+pub(super) struct Sequence {
+    kind: &'static str,
+    statements: Vec<IndexedNode>,
+    hashes: Vec<u64>,
+}
+
+impl Sequence {
+    pub(super) fn new(kind: &'static str, statements: Vec<IndexedNode>, hashes: Vec<u64>) -> Self {
+        Self {
+            kind,
+            statements,
+            hashes,
+        }
+    }
+
+    /// The ids of the statements along with their structure hashes
+    pub(super) fn statement_hashes(&self) -> impl Iterator<Item = (Id, u64)> + '_ {
+        self.statements
+            .iter()
+            .map(IndexedNode::id)
+            .zip(self.hashes.iter().copied())
+    }
+}
+
+/// Index of a sequence and of the first statement of a run inside it
+type Occurrence = (usize, usize);
+
+/// Hashes of a pair of adjacent statements, which seeds the runs starting with it
+pub(super) type Seed = (u64, u64);
+
+/// Runs of `len` statements that are identical at every occurrence
+struct Run {
+    occurrences: Vec<Occurrence>,
+    len: usize,
+}
+
+impl Engine {
+    /// Detect duplicated runs of contiguous statements inside sequence nodes such as blocks.
+    ///
+    /// Every run spans at least two statements and is extended as far as all of its
+    /// occurrences stay identical. Runs whose occurrences each lie inside a different member of
+    /// a duplicated node group with as many members are left out, as that group covers them.
+    ///
+    /// # Arguments
+    /// * `ranking` - How groups are ordered, most valuable first
+    /// * `limitation` - Optional maximum number of duplicate groups to return
+    ///
+    /// # Returns
+    /// A vector of duplicate groups, where each fragment spans a run of statements
+    pub fn detect_duplicate_sequences(
+        &self,
+        ranking: Ranking,
+        limitation: Option<usize>,
+    ) -> Vec<DuplicateGroup> {
+        self.detect_duplicate_sequences_cancellable(ranking, limitation, &CancellationToken::new())
+            .into_inner()
+    }
+
+    /// Detects duplicated runs of statements like [`Engine::detect_duplicate_sequences`] until
+    /// `cancel` is cancelled
+    ///
+    /// The groups found by then are returned as a partial outcome.
+    pub fn detect_duplicate_sequences_cancellable(
+        &self,
+        ranking: Ranking,
+        limitation: Option<usize>,
+        cancel: &CancellationToken,
+    ) -> Outcome<Vec<DuplicateGroup>> {
+        self.install(|| {
+            let coverage = self.collect_coverage(cancel);
+            let groups = self
+                .seeds
+                .par_iter()
+                .filter(|_| !cancel.is_cancelled())
+                .flat_map_iter(|occurrences| {
+                    self.seed_groups(occurrences.value(), |first| {
+                        Self::is_covered(first.iter(), &coverage)
+                    })
+                })
+                .collect();
+            Outcome::new(self.rank(groups, ranking, limitation), cancel)
+        })
+    }
+
+    /// Builds the groups of the maximal runs that start with the pair of statements occurring
+    /// at `occurrences`, leaving out the runs for which `is_covered` holds given the ids of the
+    /// first statements of their occurrences
+    ///
+    /// Runs only depend on the sequences they occur in, so the groups of a seed only change
+    /// when a sequence holding it does.
+    pub(super) fn seed_groups(
+        &self,
+        occurrences: &[(Arc<Sequence>, usize)],
+        is_covered: impl Fn(&[Id]) -> bool,
+    ) -> Vec<DuplicateGroup> {
+        let mut sequences: Vec<Arc<Sequence>> = vec![];
+        let mut numbers = FxHashMap::default();
+        let occurrences = occurrences
+            .iter()
+            .map(|(sequence, i)| {
+                let s = *numbers.entry(Arc::as_ptr(sequence)).or_insert_with(|| {
+                    sequences.push(sequence.clone());
+                    sequences.len() - 1
+                });
+                (s, *i)
+            })
+            .collect();
+        let seed = Run {
+            occurrences: remove_overlaps(occurrences, 2),
+            len: 2,
+        };
+        // Runs starting here continue runs of an earlier seed, which finds them already
+        if seed.occurrences.len() < 2 || is_left_extensible(&sequences, &seed) {
+            return vec![];
+        }
+
+        extend_runs(&sequences, seed.occurrences)
+            .into_iter()
+            .flat_map(|run| {
+                let (s, i) = run.occurrences[0];
+                let hash = sequences[s].hashes[i..i + run.len]
+                    .iter()
+                    .copied()
+                    .fold(0, merge_structure_hash);
+                let kind = sequences[s].kind;
+                let mut members: Vec<_> = run
+                    .occurrences
+                    .iter()
+                    .map(|&(s, i)| Fragment::new(sequences[s].statements[i..i + run.len].to_vec()))
+                    .collect();
+                // Statements of one run share their enclosing nodes, so the first stands for all
+                let first: Vec<_> = members
+                    .iter()
+                    .map(|fragment| fragment.nodes()[0].id())
+                    .collect();
+                if is_covered(&first) || !self.reaches_thresholds(kind, members[0].nodes()) {
+                    return vec![];
+                }
+                members.sort_by(|lhs, rhs| {
+                    (lhs.path(), lhs.byte_range()).cmp(&(rhs.path(), rhs.byte_range()))
+                });
+                self.verified_classes(members, Fragment::nodes)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, group)| group.len() > 1)
+                    .map(|(i, group)| {
+                        self.duplicate_group(class_hash(hash, i), Origin::Sequence, kind, group)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Adds every pair of adjacent statements of `sequences` to the seeds
+    pub(super) fn index_sequences(&self, sequences: &[Arc<Sequence>]) {
+        let mut touched = vec![];
+        for sequence in sequences {
+            for (i, window) in sequence.hashes.windows(2).enumerate() {
+                let seed = (window[0], window[1]);
+                self.seeds
+                    .entry(seed)
+                    .or_default()
+                    .push((sequence.clone(), i));
+                touched.push(seed);
+            }
+        }
+        self.mark_dirty_seeds(touched);
+    }
+
+    /// Removes what [`Engine::index_sequences`] added for `sequences`
+    pub(super) fn unindex_sequences(&self, sequences: &[Arc<Sequence>]) {
+        let removed: FxHashSet<_> = sequences.iter().map(Arc::as_ptr).collect();
+        let touched: FxHashSet<_> = sequences
+            .iter()
+            .flat_map(|sequence| sequence.hashes.windows(2))
+            .map(|window| (window[0], window[1]))
+            .collect();
+        for &seed in &touched {
+            if let dashmap::Entry::Occupied(mut entry) = self.seeds.entry(seed) {
+                let occurrences = entry.get_mut();
+                occurrences.retain(|(sequence, _)| !removed.contains(&Arc::as_ptr(sequence)));
+                if occurrences.is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+        self.mark_dirty_seeds(touched.into_iter().collect());
+    }
+}
+
+/// Grows the runs shared by the occurrences of a seed, returning every maximal run
+///
+/// Occurrences are split by the statement that follows the run. A subset that still agrees
+/// keeps growing on its own, and a run is only maximal if no split keeps all of its
+/// occurrences. Subsets that are left-extensible are dropped as soon as they split off, since
+/// an earlier seed grows them already.
+fn extend_runs(sequences: &[Arc<Sequence>], occurrences: Vec<Occurrence>) -> Vec<Run> {
+    let mut runs = vec![];
+    let mut pending = vec![Run {
+        occurrences,
+        len: 2,
+    }];
+    while let Some(run) = pending.pop() {
+        let mut next: FxHashMap<u64, Vec<Occurrence>> = FxHashMap::default();
+        for &(s, i) in &run.occurrences {
+            if let Some(&hash) = sequences[s].hashes.get(i + run.len) {
+                next.entry(hash).or_default().push((s, i));
+            }
+        }
+
+        let mut extensible = false;
+        for part in next.into_values() {
+            let part = Run {
+                occurrences: remove_overlaps(part, run.len + 1),
+                len: run.len + 1,
+            };
+            if part.occurrences.len() == run.occurrences.len() {
+                extensible = true;
+            }
+            if part.occurrences.len() > 1 && !is_left_extensible(sequences, &part) {
+                pending.push(part);
+            }
+        }
+        if !extensible {
+            runs.push(run);
+        }
+    }
+    runs
+}
+
+/// Whether every occurrence of the run is preceded by the same statement, in which case the
+/// run is found again from an earlier seed
+fn is_left_extensible(sequences: &[Arc<Sequence>], run: &Run) -> bool {
+    let mut previous = run
+        .occurrences
+        .iter()
+        .map(|&(s, i)| i.checked_sub(1).map(|i| sequences[s].hashes[i]));
+    match previous.next() {
+        Some(Some(first)) => previous.all(|hash| hash == Some(first)),
+        _ => false,
+    }
+}
+
+/// Drops occurrences that overlap an earlier occurrence of a run of `len` statements in the
+/// same sequence
+fn remove_overlaps(mut occurrences: Vec<Occurrence>, len: usize) -> Vec<Occurrence> {
+    occurrences.sort_unstable();
+    let mut last: Option<Occurrence> = None;
+    occurrences.retain(|&(s, i)| match last {
+        Some((last_s, last_i)) if last_s == s && i < last_i + len => false,
+        _ => {
+            last = Some((s, i));
+            true
+        }
+    });
+    occurrences
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{duplicate_group::Origin, ranking::Ranking, Engine},
+        languages::SupportedLanguage,
+    };
+
+    const RUN: &str = r#"
+    let mut total = 0;
+    for item in items {
+        if *item > 10 {
+            for _ in 0..*item {
+                if total % 2 == 0 && *item < 100 {
+                    total += item * 2;
+                }
+            }
+        } else if *item == 0 {
+            continue;
+        } else {
+            total += item;
+        }
+    }
+    while total > 100 {
+        total /= 2;
+    }
+"#;
+
+    fn engine(sources: &[String]) -> Engine {
+        let engine = Engine::new(SupportedLanguage::from_language_id("rust").unwrap());
+        for (i, source) in sources.iter().enumerate() {
+            let path = Arc::new(PathBuf::from(format!("file{i}.rs")));
+            engine.insert(path, Arc::new(source.clone())).unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn runs_shared_by_different_blocks_are_found() {
+        let engine = engine(&[format!(
+            "fn a(items: &[u32]) {{\n    start();{RUN}    log(total);\n}}\n\
+             fn b(items: &[u32]) {{{RUN}    send(total);\n}}\n"
+        )]);
+
+        let groups = engine.detect_duplicate_sequences(Ranking::default(), None);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 2);
+        assert_eq!(groups[0].origin(), Origin::Sequence);
+        for fragment in groups[0].fragments() {
+            assert_eq!(fragment.nodes().len(), 3);
+            assert!(fragment.text().starts_with("let mut total = 0;"));
+            assert!(fragment.text().ends_with("total /= 2;\n    }"));
+        }
+    }
+
+    #[test]
+    fn runs_inside_duplicated_nodes_are_left_out() {
+        let method = format!("fn a(items: &[u32]) {{{RUN}    log(total);\n}}\n");
+        let engine = engine(&[format!("impl A {{\n{method}}}\nimpl B {{\n{method}}}\n")]);
+
+        assert!(engine
+            .detect_duplicate_sequences(Ranking::default(), None)
+            .is_empty());
+    }
+
+    #[test]
+    fn long_runs_are_found_in_one_piece() {
+        const LEN: usize = 8000;
+        let run: String = (0..LEN)
+            .map(|i| format!("    if total > {i} {{\n        total -= {i};\n    }}\n"))
+            .collect();
+        let engine = engine(&[format!(
+            "fn a(mut total: u32) {{\n{run}    log(total);\n}}\n\
+             fn b(mut total: u32) {{\n{run}    send(total);\n}}\n"
+        )]);
+
+        let groups = engine.detect_duplicate_sequences(Ranking::default(), None);
+        assert_eq!(groups.len(), 1);
+        for fragment in groups[0].fragments() {
+            assert_eq!(fragment.nodes().len(), LEN);
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use crate::languages::LeafClass;
+
+use super::{fragment::Fragment, indexed_node::IndexedNode, Engine};
+
+/// How closely the members of a duplicate group resemble each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CloneKind {
+    /// Every leaf token has the same text (Type-1)
+    Exact,
+    /// Same structure, with identifiers renamed one to one and literals possibly differing,
+    /// so the members only differ by what a shared implementation would take as parameters
+    /// (Type-2)
+    Parameterized,
+    /// Same structure, but identifiers are not renamed one to one, like `a + b * a` and
+    /// `x + y * z`
+    InconsistentRename,
+    /// Some leaf tokens were added or removed (Type-3)
+    NearMiss,
+}
+
+impl std::fmt::Display for CloneKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloneKind::Exact => write!(f, "exact"),
+            CloneKind::Parameterized => write!(f, "parameterized"),
+            CloneKind::InconsistentRename => write!(f, "inconsistently renamed"),
+            CloneKind::NearMiss => write!(f, "near-miss"),
+        }
+    }
+}
+
+/// Similarity score and leaf level differences of a duplicate group
+///
+/// Every member is compared with the first member of the group, called the reference.
+#[derive(Clone)]
+pub struct Similarity {
+    kind: CloneKind,
+    score: f64,
+    differing_leaves: Vec<Vec<IndexedNode>>,
+    differing_literals: Vec<Vec<IndexedNode>>,
+    renamings: Vec<Option<Vec<(String, String)>>>,
+}
+
+impl Similarity {
+    pub fn kind(&self) -> CloneKind {
+        self.kind
+    }
+
+    /// The lowest similarity between the reference and any other member, in `[0.0, 1.0]`
+    ///
+    /// Only leaves with identical text count as equal, so renamed clones score below `1.0`.
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    /// Total number of differing leaf tokens over all members
+    pub fn differing_leaf_count(&self) -> usize {
+        self.differing_leaves.iter().map(Vec::len).sum()
+    }
+
+    /// Differing leaf tokens of each member in source order, in the order of the group
+    ///
+    /// For the reference these are the leaves that differ from at least one other member, for
+    /// other members the leaves that differ from the reference.
+    pub fn differing_leaves(&self) -> &[Vec<IndexedNode>] {
+        &self.differing_leaves
+    }
+
+    /// Literals whose text differs between members, each with its counterpart in every member
+    /// in the order of the group
+    ///
+    /// These are the parameters a shared implementation would take. Literals only match
+    /// across members when they are abstracted, see
+    /// [`LanguageConfig::abstract_literals`](crate::languages::LanguageConfig::abstract_literals).
+    pub fn differing_literals(&self) -> &[Vec<IndexedNode>] {
+        &self.differing_literals
+    }
+
+    /// How each member renames the identifiers of the reference, in the order of the group
+    ///
+    /// Every renaming lists the pairs of reference and member names that differ, in order of
+    /// first occurrence, so the reference has none. It is `None` for members that do not
+    /// rename identifiers one to one.
+    pub fn renamings(&self) -> &[Option<Vec<(String, String)>>] {
+        &self.renamings
+    }
+}
+
+impl Engine {
+    /// Computes how similar the members of a duplicate group are
+    ///
+    /// Leaves are aligned by their normalized hash, then compared by their text. Leaves without
+    /// a counterpart make the group a near-miss clone.
+    pub fn similarity(&self, group: &[Fragment]) -> Similarity {
+        let leaves: Vec<_> = group
+            .iter()
+            .map(|fragment| self.hashed_leaves(fragment.nodes()))
+            .collect();
+        let mut kind = CloneKind::Exact;
+        let mut score: f64 = 1.0;
+        let mut differing = vec![vec![false; leaves.first().map_or(0, Vec::len)]];
+        // Every leaf of the reference with the leaves aligned with it so far
+        let mut counterparts: Vec<Vec<IndexedNode>> = leaves
+            .first()
+            .map(|reference| {
+                reference
+                    .iter()
+                    .map(|(leaf, _)| vec![leaf.clone()])
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut renamings = vec![Some(vec![])];
+
+        if let Some((reference, others)) = leaves.split_first() {
+            for other in others {
+                let mut other_differing = vec![true; other.len()];
+                let pairs = align(reference, other);
+                for &(i, j) in &pairs {
+                    counterparts[i].push(other[j].0.clone());
+                }
+                // Children of unordered nodes are aligned in the order of their hashes, but
+                // names are renamed in the order they occur
+                let mut in_source_order = pairs.clone();
+                in_source_order.sort_by_key(|&(i, _)| reference[i].0.byte_range());
+                let renaming = self.renaming(
+                    in_source_order
+                        .iter()
+                        .map(|&(i, j)| (&reference[i].0, &other[j].0)),
+                );
+                if renaming.is_none() {
+                    kind = kind.max(CloneKind::InconsistentRename);
+                }
+                renamings.push(renaming);
+                if pairs.len() != reference.len() || pairs.len() != other.len() {
+                    kind = CloneKind::NearMiss;
+                }
+                score = score.min(pair_score(reference, other, &pairs));
+                let mut matched = vec![false; reference.len()];
+                for (i, j) in pairs {
+                    matched[i] = true;
+                    if reference[i].0.text() == other[j].0.text() {
+                        other_differing[j] = false;
+                    } else {
+                        differing[0][i] = true;
+                        kind = kind.max(CloneKind::Parameterized);
+                    }
+                }
+                for (i, _) in matched.iter().enumerate().filter(|(_, &x)| !x) {
+                    differing[0][i] = true;
+                }
+                differing.push(other_differing);
+            }
+        }
+
+        let differing_leaves = leaves
+            .iter()
+            .zip(differing)
+            .map(|(leaves, differing)| {
+                let mut leaves: Vec<_> = leaves
+                    .iter()
+                    .zip(differing)
+                    .filter(|(_, x)| *x)
+                    .map(|((leaf, _), _)| leaf.clone())
+                    .collect();
+                leaves.sort_by_key(IndexedNode::byte_range);
+                leaves
+            })
+            .collect();
+
+        let mut differing_literals: Vec<_> = counterparts
+            .into_iter()
+            .filter(|leaves| {
+                leaves.len() == group.len()
+                    && matches!(self.language.leaf_class(&leaves[0]), LeafClass::Literal(_))
+                    && leaves.iter().any(|leaf| leaf.text() != leaves[0].text())
+            })
+            .collect();
+        differing_literals.sort_by_key(|leaves| leaves[0].byte_range());
+
+        Similarity {
+            kind,
+            score,
+            differing_leaves,
+            differing_literals,
+            renamings,
+        }
+    }
+
+    /// Collects the hashed leaves of `nodes` in the order they are hashed, along with their
+    /// hashes
+    ///
+    /// That is from left to right, except that the children of unordered nodes come in the
+    /// order of their hashes, so that reordered children line up when aligned.
+    /// Abstracted literals count as leaves, see [`Engine::is_token`].
+    pub(super) fn hashed_leaves(&self, nodes: &[IndexedNode]) -> Vec<(IndexedNode, u64)> {
+        let mut leaves = vec![];
+        for node in nodes {
+            self.structure_hash(node, &mut |node, hash| {
+                if self.is_token(node) {
+                    leaves.push((node.clone(), hash));
+                }
+            });
+        }
+        leaves
+    }
+}
+
+/// The similarity of two leaf sequences as reported by [`Similarity::score`]: twice the
+/// number of aligned leaves with the same text over the number of leaves of both
+pub(super) fn leaf_similarity(lhs: &[(IndexedNode, u64)], rhs: &[(IndexedNode, u64)]) -> f64 {
+    pair_score(lhs, rhs, &align(lhs, rhs))
+}
+
+fn pair_score(
+    lhs: &[(IndexedNode, u64)],
+    rhs: &[(IndexedNode, u64)],
+    pairs: &[(usize, usize)],
+) -> f64 {
+    let total = lhs.len() + rhs.len();
+    if total == 0 {
+        return 1.0;
+    }
+    let equal = pairs
+        .iter()
+        .filter(|&&(i, j)| lhs[i].0.text() == rhs[j].0.text())
+        .count();
+    (2 * equal) as f64 / total as f64
+}
+
+/// Aligns two leaf sequences by hash, returning the index pairs of the longest common
+/// subsequence in ascending order
+fn align(lhs: &[(IndexedNode, u64)], rhs: &[(IndexedNode, u64)]) -> Vec<(usize, usize)> {
+    let hashes = |leaves: &[(IndexedNode, u64)]| leaves.iter().map(|(_, hash)| *hash).collect();
+    let (lhs, rhs): (Vec<_>, Vec<_>) = (hashes(lhs), hashes(rhs));
+    align_hashes(&lhs, &rhs)
+}
+
+/// Aligns two hash sequences like [`align`]
+///
+/// The common prefix and suffix are paired up directly and what is left between them with
+/// Hirschberg's algorithm, which takes space linear in the length of the sequences.
+fn align_hashes(lhs: &[u64], rhs: &[u64]) -> Vec<(usize, usize)> {
+    let prefix = lhs.iter().zip(rhs).take_while(|(x, y)| x == y).count();
+    let suffix = lhs[prefix..]
+        .iter()
+        .rev()
+        .zip(rhs[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut pairs: Vec<_> = (0..prefix).map(|i| (i, i)).collect();
+    hirschberg(
+        &lhs[prefix..lhs.len() - suffix],
+        &rhs[prefix..rhs.len() - suffix],
+        (prefix, prefix),
+        &mut pairs,
+    );
+    pairs.extend((0..suffix).map(|k| (lhs.len() - suffix + k, rhs.len() - suffix + k)));
+    pairs
+}
+
+/// Pushes the index pairs of the longest common subsequence of `lhs` and `rhs` in ascending
+/// order, offset by `offset`
+///
+/// `lhs` is split in half and `rhs` where the lengths of the common subsequences of the
+/// halves add up to the most, then both parts are aligned on their own.
+fn hirschberg(lhs: &[u64], rhs: &[u64], offset: (usize, usize), pairs: &mut Vec<(usize, usize)>) {
+    if lhs.is_empty() || rhs.is_empty() {
+        return;
+    }
+    if let [hash] = lhs {
+        if let Some(j) = rhs.iter().position(|x| x == hash) {
+            pairs.push((offset.0, offset.1 + j));
+        }
+        return;
+    }
+    let mid = lhs.len() / 2;
+    let forward = lcs_lengths(lhs[..mid].iter(), rhs.iter());
+    let backward = lcs_lengths(lhs[mid..].iter().rev(), rhs.iter().rev());
+    let split = (0..=rhs.len())
+        .max_by_key(|&j| (forward[j] + backward[rhs.len() - j], std::cmp::Reverse(j)))
+        .unwrap_or_default();
+    hirschberg(&lhs[..mid], &rhs[..split], offset, pairs);
+    hirschberg(
+        &lhs[mid..],
+        &rhs[split..],
+        (offset.0 + mid, offset.1 + split),
+        pairs,
+    );
+}
+
+/// The lengths of the longest common subsequences of `lhs` and every prefix of `rhs`
+fn lcs_lengths<'a>(
+    lhs: impl Iterator<Item = &'a u64>,
+    rhs: impl Iterator<Item = &'a u64> + Clone,
+) -> Vec<u32> {
+    let mut row = vec![0; rhs.clone().count() + 1];
+    for x in lhs {
+        // The length for the previous prefix of `rhs`, before the row was updated for `x`
+        let mut diagonal = 0;
+        for (j, y) in rhs.clone().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if x == y {
+                diagonal + 1
+            } else {
+                above.max(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::{align_hashes, CloneKind};
+    use crate::{
+        engine::{fragment::Fragment, indexed_node::IndexedNode, Engine},
+        languages::{LanguageConfig, SupportedLanguage},
+    };
+
+    /// Indexes every source under its own path and returns the first item of each
+    fn items(sources: &[&str]) -> (Engine, Vec<Fragment>) {
+        items_with(LanguageConfig::default(), sources)
+    }
+
+    fn items_with(config: LanguageConfig, sources: &[&str]) -> (Engine, Vec<Fragment>) {
+        let language = SupportedLanguage::from_language_id_with_config("rust", config).unwrap();
+        let engine = Engine::new(language);
+        let mut items = vec![];
+        for (i, source) in sources.iter().enumerate() {
+            let path = Arc::new(PathBuf::from(format!("file{i}.rs")));
+            engine
+                .insert(path.clone(), Arc::new(source.to_string()))
+                .unwrap();
+            let root = engine.tree_map.get(&path).unwrap().root_node();
+            items.push(Fragment::from(root.children().next().unwrap()));
+        }
+        (engine, items)
+    }
+
+    fn texts(leaves: &[IndexedNode]) -> Vec<&str> {
+        leaves.iter().map(|leaf| leaf.text()).collect()
+    }
+
+    #[test]
+    fn identical_members_are_exact_clones() {
+        let source = "fn f(x: u32) -> u32 { x + 1 }";
+        let (engine, items) = items(&[source, source]);
+        let similarity = engine.similarity(&items);
+        assert_eq!(similarity.kind(), CloneKind::Exact);
+        assert_eq!(similarity.score(), 1.0);
+        assert_eq!(similarity.differing_leaf_count(), 0);
+    }
+
+    #[test]
+    fn renamed_leaves_are_reported_for_both_sides() {
+        let (engine, items) = items(&[
+            "fn f(x: u32) -> u32 { x + 1 }",
+            "fn f(y: u32) -> u32 { y + 1 }",
+        ]);
+        let similarity = engine.similarity(&items);
+        assert_eq!(similarity.kind(), CloneKind::Parameterized);
+        assert!(similarity.score() < 1.0);
+        assert_eq!(texts(&similarity.differing_leaves()[0]), ["x", "x"]);
+        assert_eq!(texts(&similarity.differing_leaves()[1]), ["y", "y"]);
+    }
+
+    #[test]
+    fn added_leaves_make_a_near_miss_clone() {
+        let (engine, items) = items(&[
+            "fn f(x: u32) -> u32 { let y = x; y + 1 }",
+            "fn f(x: u32) -> u32 { let y = x; log(y); y + 1 }",
+        ]);
+        let similarity = engine.similarity(&items);
+        assert_eq!(similarity.kind(), CloneKind::NearMiss);
+        assert_eq!(
+            texts(&similarity.differing_leaves()[1]),
+            ["log", "(", "y", ")", ";"]
+        );
+    }
+
+    #[test]
+    fn differing_abstracted_literals_are_listed() {
+        let config = LanguageConfig {
+            abstract_literals: true,
+            ..LanguageConfig::default()
+        };
+        let (engine, items) = items_with(
+            config,
+            &[
+                r#"fn f() { send("to \"a\"", 1, true) }"#,
+                r#"fn f() { send("to b", 1, false) }"#,
+                r#"fn f() { send("to c", 1, false) }"#,
+            ],
+        );
+        let similarity = engine.similarity(&items);
+        let literals: Vec<_> = similarity
+            .differing_literals()
+            .iter()
+            .map(|literals| texts(literals))
+            .collect();
+        assert_eq!(
+            literals,
+            [
+                vec![r#""to \"a\"""#, r#""to b""#, r#""to c""#],
+                vec!["true", "false", "false"]
+            ]
+        );
+    }
+
+    #[test]
+    fn consistent_renamings_are_parameterized_clones() {
+        let (engine, items) = items(&[
+            "fn f(a: u32, b: u32) -> u32 { a + b * a }",
+            "fn f(x: u32, y: u32) -> u32 { x + y * x }",
+        ]);
+        let similarity = engine.similarity(&items);
+        assert_eq!(similarity.kind(), CloneKind::Parameterized);
+        let renaming = [("a", "x"), ("b", "y")].map(|(a, b)| (a.to_string(), b.to_string()));
+        assert_eq!(
+            similarity.renamings(),
+            [Some(vec![]), Some(renaming.to_vec())]
+        );
+    }
+
+    #[test]
+    fn inconsistent_renamings_are_told_apart() {
+        let (engine, items) = items(&[
+            "fn f(a: u32, b: u32) -> u32 { a + b * a }",
+            "fn f(x: u32, y: u32) -> u32 { x + y * y }",
+        ]);
+        let similarity = engine.similarity(&items);
+        assert_eq!(similarity.kind(), CloneKind::InconsistentRename);
+        assert_eq!(similarity.renamings(), [Some(vec![]), None]);
+    }
+
+    #[test]
+    fn alignments_are_longest_common_subsequences() {
+        let cases: [(&[u64], &[u64], usize); 5] = [
+            (&[1, 2, 3, 2, 4, 1, 2], &[2, 4, 3, 1, 2, 1], 4),
+            (&[1, 2, 3], &[], 0),
+            (&[1, 2, 3], &[4, 5], 0),
+            (&[7, 1, 2, 3, 7], &[7, 3, 2, 1, 7], 3),
+            (&[1, 1, 1, 2], &[2, 1, 1], 2),
+        ];
+        for (lhs, rhs, len) in cases {
+            let pairs = align_hashes(lhs, rhs);
+            assert_eq!(pairs.len(), len);
+            assert!(pairs.iter().all(|&(i, j)| lhs[i] == rhs[j]));
+            assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+        }
+    }
+
+    #[test]
+    fn reordered_fields_pair_up_by_name() {
+        let (engine, items) = items(&[
+            "fn f() -> P { P { x: a, y: b } }",
+            "fn f() -> P { P { y: d, x: c } }",
+        ]);
+        let similarity = engine.similarity(&items);
+        assert_eq!(similarity.kind(), CloneKind::Parameterized);
+        assert_eq!(texts(&similarity.differing_leaves()[0]), ["a", "b"]);
+        assert_eq!(texts(&similarity.differing_leaves()[1]), ["d", "c"]);
+        let renaming = [("a", "c"), ("b", "d")].map(|(a, b)| (a.to_string(), b.to_string()));
+        assert_eq!(similarity.renamings()[1], Some(renaming.to_vec()));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{languages::NodeTaste, utils::hash::merge_structure_hash};
+
+use super::{indexed_node::IndexedNode, Engine};
+
+impl Engine {
+    /// Splits items sharing a structure hash into classes of structurally equal items
+    ///
+    /// Without verification all items form a single class. Otherwise classes are ordered by
+    /// the first item in each of them, so that items ordered by location give stable classes.
+    /// With consistent renaming required, the items of a class also map their identifiers one
+    /// to one, see [`EngineBuilder::consistent_renaming`](super::builder::EngineBuilder::consistent_renaming).
+    pub(super) fn verified_classes<T>(
+        &self,
+        items: Vec<T>,
+        nodes: impl Fn(&T) -> &[IndexedNode],
+    ) -> Vec<Vec<T>> {
+        if !self.verify && !self.consistent_renaming {
+            return vec![items];
+        }
+        let mut classes: Vec<Vec<T>> = vec![];
+        for item in items {
+            let class = classes.iter_mut().find(|class| {
+                let (lhs, rhs) = (nodes(&class[0]), nodes(&item));
+                lhs.len() == rhs.len()
+                    && (!self.verify
+                        || lhs
+                            .iter()
+                            .zip(rhs)
+                            .all(|(lhs, rhs)| self.structurally_equal(lhs, rhs)))
+                    && (!self.consistent_renaming || self.consistently_renamed(lhs, rhs))
+            });
+            match class {
+                Some(class) => class.push(item),
+                None => classes.push(vec![item]),
+            }
+        }
+        classes
+    }
+
+    /// Whether two subtrees have the same kinds and normalized tokens at every position
+    ///
+    /// Nodes left out of structure hashes, like comments and error nodes, are left out here
+    /// as well, and the children of unordered nodes are compared in the order of their hashes.
+    pub(super) fn structurally_equal(&self, lhs: &IndexedNode, rhs: &IndexedNode) -> bool {
+        let mut stack = vec![(lhs.clone(), rhs.clone())];
+        while let Some((lhs, rhs)) = stack.pop() {
+            if lhs.kind_id() != rhs.kind_id() {
+                return false;
+            }
+            match (self.is_token(&lhs), self.is_token(&rhs)) {
+                (true, true) => {
+                    // Texts rather than their hashes, which could collide as well
+                    if self.language.normalized_text(&lhs) != self.language.normalized_text(&rhs) {
+                        return false;
+                    }
+                    continue;
+                }
+                (false, false) => {}
+                _ => return false,
+            }
+            let mut lhs_children: Vec<_> = self.hashed_children(&lhs).collect();
+            let mut rhs_children: Vec<_> = self.hashed_children(&rhs).collect();
+            if lhs_children.len() != rhs_children.len() {
+                return false;
+            }
+            if self.language.indexed_node_is_unordered(&lhs) {
+                // Pairs up children the way they were merged into the structure hash
+                for children in [&mut lhs_children, &mut rhs_children] {
+                    children.sort_by_cached_key(|child| self.structure_hash(child, &mut |_, _| {}));
+                }
+            }
+            stack.extend(lhs_children.into_iter().zip(rhs_children));
+        }
+        true
+    }
+
+    /// The children of `node` that contribute to its structure hash
+    fn hashed_children<'a>(
+        &'a self,
+        node: &'a IndexedNode,
+    ) -> impl Iterator<Item = IndexedNode> + 'a {
+        node.children().filter(|child| {
+            !child.is_extra_or_missing_or_error()
+                && self.language.indexed_node_taste(child) != NodeTaste::Ignored
+        })
+    }
+}
+
+/// The hash of the `index`th class split off a group with `hash`, see
+/// [`Engine::verified_classes`]
+///
+/// The first class keeps the hash, so groups without collisions are unaffected.
+pub(super) fn class_hash(hash: u64, index: usize) -> u64 {
+    if index == 0 {
+        hash
+    } else {
+        merge_structure_hash(hash, index as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        engine::{indexed_node::IndexedNode, Engine},
+        languages::SupportedLanguage,
+    };
+
+    /// The call expressions of `source`, ordered by location
+    fn calls(engine: &Engine, source: &str) -> Vec<IndexedNode> {
+        let path = Arc::new(PathBuf::from("a.rs"));
+        engine
+            .insert(path.clone(), Arc::new(source.to_string()))
+            .unwrap();
+        let root = engine.tree_map.get(&path).unwrap().root_node();
+        let mut calls: Vec<_> = IndexedNode::all_children(root)
+            .into_iter()
+            .filter(|node| node.kind() == "call_expression")
+            .collect();
+        calls.sort_by_key(|node| node.byte_range());
+        calls
+    }
+
+    fn verifying_engine(verify: bool) -> Engine {
+        Engine::builder(SupportedLanguage::from_language_id("rust").unwrap())
+            .verify(verify)
+            .build()
+    }
+
+    #[test]
+    fn colliding_items_are_split_into_equal_classes() {
+        let engine = verifying_engine(true);
+        // As if the three calls had collided on one hash
+        let calls = calls(
+            &engine,
+            "fn f() {\n    send(1);\n    send(2);\n    send(1);\n}\n",
+        );
+
+        let classes = engine.verified_classes(calls, std::slice::from_ref);
+        let texts: Vec<Vec<_>> = classes
+            .iter()
+            .map(|class| class.iter().map(|node| node.text()).collect())
+            .collect();
+        assert_eq!(texts, [vec!["send(1)", "send(1)"], vec!["send(2)"]]);
+    }
+
+    #[test]
+    fn items_form_one_class_without_verification() {
+        let engine = verifying_engine(false);
+        let calls = calls(&engine, "fn f() {\n    send(1);\n    send(2);\n}\n");
+
+        assert_eq!(
+            engine.verified_classes(calls, std::slice::from_ref).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn comments_are_left_out_of_the_comparison() {
+        let engine = verifying_engine(true);
+        let calls = calls(
+            &engine,
+            "fn f() {\n    send(1 /* one */);\n    send(1);\n}\n",
+        );
+
+        assert!(engine.structurally_equal(&calls[0], &calls[1]));
+    }
+
+    #[test]
+    fn tokens_are_compared_by_normalized_text() {
+        let engine = verifying_engine(true);
+        let calls = calls(
+            &engine,
+            "fn f(a: u8, b: u8) {\n    send(a);\n    send(b);\n    send(\"a\");\n    send(\"b\");\n}\n",
+        );
+
+        // Variables are renamed by default, string literals are not abstracted
+        assert!(engine.structurally_equal(&calls[0], &calls[1]));
+        assert!(!engine.structurally_equal(&calls[2], &calls[3]));
+    }
+}
@@ -5,9 +5,13 @@ use std::ops::Deref;
 
 use python::Python;
 use rust::Rust;
+use rustc_hash::FxHashMap;
 use tree_sitter::{InputEdit, Parser, Query};
 
-use crate::engine::indexed_node::IndexedNode;
+use crate::{
+    engine::indexed_node::IndexedNode,
+    utils::hash::{stable_hash, DEFAULT_SEED},
+};
 
 pub enum SupportedLanguage {
     Python(Python),
@@ -23,10 +27,24 @@ impl SupportedLanguage {
         matches!(language_id, "python" | "rust")
     }
 
+    pub fn language_id(&self) -> &'static str {
+        match self {
+            SupportedLanguage::Python(_) => "python",
+            SupportedLanguage::Rust(_) => "rust",
+        }
+    }
+
     pub fn from_language_id<T: AsRef<str>>(language_id: T) -> Option<SupportedLanguage> {
+        Self::from_language_id_with_config(language_id, LanguageConfig::default())
+    }
+
+    pub fn from_language_id_with_config<T: AsRef<str>>(
+        language_id: T,
+        config: LanguageConfig,
+    ) -> Option<SupportedLanguage> {
         match language_id.as_ref() {
-            "python" => Some(SupportedLanguage::Python(Python::default())),
-            "rust" => Some(SupportedLanguage::Rust(Rust::default())),
+            "python" => Some(SupportedLanguage::Python(Python::new(config))),
+            "rust" => Some(SupportedLanguage::Rust(Rust::new(config))),
             _ => None,
         }
     }
@@ -42,6 +60,146 @@ impl Deref for SupportedLanguage {
     }
 }
 
+/// Settings shared by all languages
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguageConfig {
+    /// Seed of the leaf and structure hashes
+    ///
+    /// Hashes are deterministic: the same sources hashed with the same seed give the same
+    /// hashes in every process, so they can be persisted and compared between runs.
+    pub seed: u64,
+    /// Which leaves are compared by what they are rather than by their text
+    pub normalization: Normalization,
+    /// Whether literals are compared by their [`LiteralKind`] rather than by their text, so
+    /// that fragments differing only by a message or a constant still match
+    pub abstract_literals: bool,
+    /// Thresholds replacing those of the language, see [`Language::thresholds`]
+    pub thresholds: Option<Thresholds>,
+    /// Thresholds of fragments by node kind, replacing both the thresholds above and those the
+    /// language sets for the kind, see [`Language::thresholds_of`]
+    pub kind_thresholds: FxHashMap<String, Thresholds>,
+}
+
+impl LanguageConfig {
+    /// Whether leaves of `class` hash as the placeholder of the class
+    pub fn normalizes(&self, class: LeafClass) -> bool {
+        match class {
+            LeafClass::Literal(_) => self.abstract_literals,
+            _ => self.normalization.normalizes(class),
+        }
+    }
+}
+
+impl Default for LanguageConfig {
+    fn default() -> Self {
+        Self {
+            seed: DEFAULT_SEED,
+            normalization: Normalization::default(),
+            abstract_literals: false,
+            thresholds: None,
+            kind_thresholds: FxHashMap::default(),
+        }
+    }
+}
+
+/// Minimum size of a fragment worth reporting as a duplicate
+///
+/// A fragment has to reach every minimum, so that neither long but trivial fragments nor
+/// short but intricate ones swamp the results. A minimum of zero always holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Cognitive complexity, see [`Language::indexed_node_cognitive_complexity`]
+    pub complexity: f64,
+    /// Lines spanned
+    pub lines: usize,
+    /// Tokens, a literal counting as one when literals are abstracted
+    pub tokens: usize,
+    /// Syntax nodes, tokens included, leaving out the nodes that are not hashed, like comments
+    pub nodes: usize,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            complexity: 10.0,
+            lines: 0,
+            tokens: 0,
+            nodes: 0,
+        }
+    }
+}
+
+/// How strictly fragments must match to be duplicates, the same for every language
+///
+/// Leaves that are normalized hash the same as every other leaf of their [`LeafClass`], so
+/// fragments that only differ there still match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Normalization {
+    /// Leaves must have the same text, which finds type-1 clones
+    Exact,
+    /// Variable names may differ, which finds type-2 clones
+    #[default]
+    Renamed,
+    /// Variable, type and function names may differ
+    Abstracted,
+}
+
+impl Normalization {
+    /// Whether leaves of `class` are normalized at this level
+    ///
+    /// Literals are not part of the levels, see [`LanguageConfig::abstract_literals`].
+    pub fn normalizes(&self, class: LeafClass) -> bool {
+        matches!(
+            (self, class),
+            (Self::Renamed | Self::Abstracted, LeafClass::Variable)
+                | (Self::Abstracted, LeafClass::Type | LeafClass::Function)
+        )
+    }
+}
+
+/// What a leaf names, as far as normalization is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafClass {
+    /// Names of variables and parameters
+    Variable,
+    /// Names of types, classes and constructors
+    Type,
+    /// Names of functions, methods and macros
+    Function,
+    /// Literals, which are classified as a whole even when they have inner nodes, like
+    /// strings with escape sequences
+    Literal(LiteralKind),
+    /// Keywords, operators, fields, constants and everything else
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LiteralKind {
+    String,
+    Char,
+    Number,
+    Boolean,
+    /// Literals standing for no value, like `None`
+    Null,
+}
+
+impl LeafClass {
+    /// The text every normalized leaf of this class hashes as
+    fn placeholder(&self) -> &'static str {
+        match self {
+            Self::Variable => "variable",
+            Self::Type => "type",
+            Self::Function => "function",
+            Self::Literal(LiteralKind::String) => "string literal",
+            Self::Literal(LiteralKind::Char) => "char literal",
+            Self::Literal(LiteralKind::Number) => "number literal",
+            Self::Literal(LiteralKind::Boolean) => "boolean literal",
+            Self::Literal(LiteralKind::Null) => "null literal",
+            Self::Other => "other",
+        }
+    }
+}
+
 #[derive(PartialEq, Eq)]
 pub enum NodeTaste {
     Ignored,
@@ -53,22 +211,45 @@ pub trait Language {
     /// Returns the tree-sitter Language definition for this programming language
     fn language(&self) -> &tree_sitter::Language;
 
+    /// Returns the configuration this language was created with
+    fn config(&self) -> &LanguageConfig;
+
     /// Returns the syntax highlighting query used to identify language constructs
     fn query(&self) -> &Query;
 
     /// Creates and configures a new tree-sitter Parser instance for this language
     fn parser(&self) -> Parser;
 
+    /// Classifies a leaf for normalization, typically by its highlighting query capture
+    ///
+    /// Literal nodes are classified as [`LeafClass::Literal`] whether they are leaves or not.
+    fn leaf_class(&self, node: &IndexedNode) -> LeafClass;
+
+    /// The text a leaf stands for once normalized
+    ///
+    /// Leaves that the configuration normalizes stand for the placeholder of their
+    /// [`LeafClass`], every other one for its own text, see [`LanguageConfig::normalizes`].
+    fn normalized_text<'a>(&self, node: &'a IndexedNode) -> &'a str {
+        let class = self.leaf_class(node);
+        if self.config().normalizes(class) {
+            class.placeholder()
+        } else {
+            node.text()
+        }
+    }
+
     /// Computes a hash value for a single syntax node
     ///
+    /// Leaves hash as their normalized text, see [`Language::normalized_text`].
+    ///
     /// # Arguments
     /// * `node` - The syntax node to hash
-    /// * `query_index` - Optional index into the highlighting query results
-    /// * `source` - The source code bytes
     ///
     /// # Returns
     /// A 64-bit hash value representing the node's content
-    fn simple_hash_indexed_node(&self, node: &IndexedNode) -> u64;
+    fn simple_hash_indexed_node(&self, node: &IndexedNode) -> u64 {
+        stable_hash(self.config().seed, self.normalized_text(node).as_bytes())
+    }
 
     /// Determines the importance level of a syntax node for analysis
     ///
@@ -82,13 +263,45 @@ pub trait Language {
     /// - Normal: Processed normally
     fn indexed_node_taste(&self, node: &IndexedNode) -> NodeTaste;
 
+    /// Determines whether a syntax node holds a sequence of statements, like a block
+    ///
+    /// Contiguous runs of statements inside such nodes are candidates for duplication detection
+    /// even when no single statement is interesting on its own.
+    fn indexed_node_is_sequence(&self, node: &IndexedNode) -> bool;
+
+    /// Determines whether the order of the children of a syntax node does not matter, like the
+    /// fields of a struct or the entries of a dictionary
+    ///
+    /// Such nodes hash the same whichever way their children are ordered.
+    fn indexed_node_is_unordered(&self, node: &IndexedNode) -> bool;
+
     fn indexed_node_cognitive_complexity(&self, node: &IndexedNode) -> f64;
 
-    /// Returns the minimum cognitive complexity threshold for considering a node interesting
+    /// Returns the minimum size of fragments worth reporting, of any kind
+    fn thresholds(&self) -> Thresholds {
+        Thresholds::default()
+    }
+
+    /// Returns the minimum size of fragments of `kind`, if it differs from
+    /// [`Language::thresholds`]
+    fn kind_thresholds(&self, _kind: &str) -> Option<Thresholds> {
+        None
+    }
+
+    /// Returns the minimum size of fragments of `kind` worth reporting
     ///
-    /// Nodes with complexity scores above this threshold are candidates for duplication detection
-    fn complexity_threshold(&self) -> f64 {
-        10.0
+    /// The most specific thresholds win: those configured for the kind, then those the
+    /// language sets for it, then the configured thresholds and last those of the language,
+    /// see [`LanguageConfig::kind_thresholds`].
+    fn thresholds_of(&self, kind: &str) -> Thresholds {
+        let config = self.config();
+        config
+            .kind_thresholds
+            .get(kind)
+            .copied()
+            .or_else(|| self.kind_thresholds(kind))
+            .or(config.thresholds)
+            .unwrap_or_else(|| self.thresholds())
     }
 
     fn parse(&self, text: &str) -> Option<tree_sitter::Tree> {
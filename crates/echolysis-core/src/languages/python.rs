@@ -1,19 +1,34 @@
-use phf::phf_set;
+use phf::{phf_map, phf_set};
 use tree_sitter::{Parser, Query};
 
 use crate::engine::indexed_node::IndexedNode;
 
-use super::{Language, NodeTaste};
+use super::{Language, LanguageConfig, LeafClass, LiteralKind, NodeTaste};
 
 pub struct Python {
-    hash_builder: ahash::RandomState,
+    config: LanguageConfig,
     query: Query,
     query_names: Vec<String>,
     language: tree_sitter::Language,
 }
 
-const QUERY_TO_OBFUSCATE: phf::Set<&str> = phf_set! {
-    "variable"
+const QUERY_CLASSES: phf::Map<&str, LeafClass> = phf_map! {
+    "type" => LeafClass::Type,
+    "constructor" => LeafClass::Type,
+    "function" => LeafClass::Function,
+    "function.method" => LeafClass::Function,
+    "function.builtin" => LeafClass::Function,
+    "variable" => LeafClass::Variable,
+};
+
+const LITERAL_NODES: phf::Map<&str, LiteralKind> = phf_map! {
+    "string" => LiteralKind::String,
+    "concatenated_string" => LiteralKind::String,
+    "integer" => LiteralKind::Number,
+    "float" => LiteralKind::Number,
+    "true" => LiteralKind::Boolean,
+    "false" => LiteralKind::Boolean,
+    "none" => LiteralKind::Null,
 };
 
 const PY_INTERESTING_NODES: phf::Set<&str> = phf_set! {
@@ -28,12 +43,27 @@ const PY_INTERESTING_NODES: phf::Set<&str> = phf_set! {
     "class_definition",
 };
 
+const PY_SEQUENCE_NODES: phf::Set<&str> = phf_set! {
+    "block",
+};
+
+const PY_UNORDERED_NODES: phf::Set<&str> = phf_set! {
+    "dictionary",
+    "set",
+};
+
 const PY_IGNORED_NODES: phf::Set<&str> = phf_set! {
     "comment",
 };
 
 impl Default for Python {
     fn default() -> Self {
+        Self::new(LanguageConfig::default())
+    }
+}
+
+impl Python {
+    pub fn new(config: LanguageConfig) -> Self {
         let language: tree_sitter::Language = tree_sitter_python::LANGUAGE.into();
         let query = Query::new(&language, tree_sitter_python::HIGHLIGHTS_QUERY).unwrap();
         let query_names = query
@@ -42,7 +72,7 @@ impl Default for Python {
             .map(|x| x.to_string())
             .collect();
         Self {
-            hash_builder: ahash::RandomState::new(),
+            config,
             query,
             query_names,
             language,
@@ -55,6 +85,10 @@ impl Language for Python {
         &self.language
     }
 
+    fn config(&self) -> &LanguageConfig {
+        &self.config
+    }
+
     fn parser(&self) -> Parser {
         let mut parser = Parser::new();
         // SAFETY: We know the language is valid
@@ -66,14 +100,14 @@ impl Language for Python {
         &self.query
     }
 
-    fn simple_hash_indexed_node(&self, node: &IndexedNode) -> u64 {
-        if let Some(index) = node.query_index() {
-            let query = &self.query_names[index];
-            if QUERY_TO_OBFUSCATE.contains(query) {
-                return self.hash_builder.hash_one(query);
-            }
+    fn leaf_class(&self, node: &IndexedNode) -> LeafClass {
+        if let Some(&kind) = LITERAL_NODES.get(node.kind()) {
+            return LeafClass::Literal(kind);
         }
-        self.hash_builder.hash_one(node.text())
+        node.query_index()
+            .and_then(|index| QUERY_CLASSES.get(self.query_names[index].as_str()))
+            .copied()
+            .unwrap_or(LeafClass::Other)
     }
 
     fn indexed_node_taste(&self, node: &IndexedNode) -> NodeTaste {
@@ -86,13 +120,17 @@ impl Language for Python {
         }
     }
 
+    fn indexed_node_is_sequence(&self, node: &IndexedNode) -> bool {
+        PY_SEQUENCE_NODES.contains(node.kind())
+    }
+
+    fn indexed_node_is_unordered(&self, node: &IndexedNode) -> bool {
+        PY_UNORDERED_NODES.contains(node.kind())
+    }
+
     fn indexed_node_cognitive_complexity(&self, node: &IndexedNode) -> f64 {
-        let mut stack = vec![node];
         let mut res = 0.0;
-        while let Some(node) = stack.pop() {
-            for child in node.children() {
-                stack.push(child);
-            }
+        node.preorder_traverse(|node| {
             let node_kind = node.kind();
             if node_kind.contains("statement")
                 || node_kind.contains("call")
@@ -100,7 +138,7 @@ impl Language for Python {
             {
                 res += 1.0;
             }
-        }
+        });
         res
     }
 }
@@ -3,28 +3,37 @@ use tree_sitter::{Parser, Query};
 
 use crate::engine::indexed_node::IndexedNode;
 
-use super::{Language, NodeTaste};
+use super::{Language, LanguageConfig, LeafClass, LiteralKind, NodeTaste};
 
 pub struct Rust {
-    hash_builder: ahash::RandomState,
+    config: LanguageConfig,
     query: Query,
     query_names: Vec<String>,
     language: tree_sitter::Language,
 }
 
-const QUERY_NOT_TO_OBFUSCATE: phf::Set<&str> = phf_set! {
-    "type",
-    "constant",
-    "function",
-    "constructor",
-    "label"
+const QUERY_CLASSES: phf::Map<&str, LeafClass> = phf_map! {
+    "type" => LeafClass::Type,
+    "type.builtin" => LeafClass::Type,
+    "constructor" => LeafClass::Type,
+    "function" => LeafClass::Function,
+    "function.method" => LeafClass::Function,
+    "function.macro" => LeafClass::Function,
+    "variable.parameter" => LeafClass::Variable,
+    "constant" => LeafClass::Other,
+    "label" => LeafClass::Other,
 };
 
-const QUERY_TO_OBFUSCATE: phf::Set<&str> = phf_set! {
-    "variable.parameter"
+const LITERAL_NODES: phf::Map<&str, LiteralKind> = phf_map! {
+    "string_literal" => LiteralKind::String,
+    "raw_string_literal" => LiteralKind::String,
+    "char_literal" => LiteralKind::Char,
+    "integer_literal" => LiteralKind::Number,
+    "float_literal" => LiteralKind::Number,
+    "boolean_literal" => LiteralKind::Boolean,
 };
 
-const NODES_TO_OBFUSCATE: phf::Set<&str> = phf_set! {
+const VARIABLE_NODES: phf::Set<&str> = phf_set! {
     "identifier"
 };
 
@@ -42,6 +51,16 @@ const RUST_INTERESTING_NODES: phf::Set<&str> = phf_set! {
     "closure_expression",
 };
 
+const RUST_SEQUENCE_NODES: phf::Set<&str> = phf_set! {
+    "block",
+};
+
+const RUST_UNORDERED_NODES: phf::Set<&str> = phf_set! {
+    "field_initializer_list",
+    "field_declaration_list",
+    "use_list",
+};
+
 const RUST_IGNORED_NODES: phf::Set<&str> = phf_set! {
     "block_comment",
     "doc_comment",
@@ -98,6 +117,12 @@ const COGNITIVE_COMPLEXITY_WEIGHT: phf::Map<&str, f64> = phf_map! {
 
 impl Default for Rust {
     fn default() -> Self {
+        Self::new(LanguageConfig::default())
+    }
+}
+
+impl Rust {
+    pub fn new(config: LanguageConfig) -> Self {
         let language: tree_sitter::Language = tree_sitter_rust::LANGUAGE.into();
         let query = Query::new(&language, tree_sitter_rust::HIGHLIGHTS_QUERY).unwrap();
         let query_names = query
@@ -106,7 +131,7 @@ impl Default for Rust {
             .map(|x| x.to_string())
             .collect();
         Self {
-            hash_builder: ahash::RandomState::new(),
+            config,
             query: Query::new(&language, tree_sitter_rust::HIGHLIGHTS_QUERY).unwrap(),
             query_names,
             language,
@@ -119,6 +144,10 @@ impl Language for Rust {
         &self.language
     }
 
+    fn config(&self) -> &LanguageConfig {
+        &self.config
+    }
+
     fn parser(&self) -> Parser {
         let mut parser = Parser::new();
         // SAFETY: We know the language is valid
@@ -130,20 +159,20 @@ impl Language for Rust {
         &self.query
     }
 
-    fn simple_hash_indexed_node(&self, node: &IndexedNode) -> u64 {
-        if let Some(index) = node.query_index() {
-            let query = &self.query_names[index];
-            if QUERY_NOT_TO_OBFUSCATE.contains(query) {
-                return self.hash_builder.hash_one(node.text());
-            }
-            if QUERY_TO_OBFUSCATE.contains(query) {
-                return self.hash_builder.hash_one(query);
-            }
+    fn leaf_class(&self, node: &IndexedNode) -> LeafClass {
+        if let Some(&kind) = LITERAL_NODES.get(node.kind()) {
+            return LeafClass::Literal(kind);
+        }
+        let query = node
+            .query_index()
+            .map(|index| self.query_names[index].as_str());
+        if let Some(&class) = query.and_then(|query| QUERY_CLASSES.get(query)) {
+            return class;
         }
-        if NODES_TO_OBFUSCATE.contains(node.kind()) {
-            return self.hash_builder.hash_one(node.kind());
+        if VARIABLE_NODES.contains(node.kind()) {
+            return LeafClass::Variable;
         }
-        self.hash_builder.hash_one(node.text())
+        LeafClass::Other
     }
 
     fn indexed_node_taste(&self, node: &IndexedNode) -> NodeTaste {
@@ -156,17 +185,21 @@ impl Language for Rust {
         }
     }
 
+    fn indexed_node_is_sequence(&self, node: &IndexedNode) -> bool {
+        RUST_SEQUENCE_NODES.contains(node.kind())
+    }
+
+    fn indexed_node_is_unordered(&self, node: &IndexedNode) -> bool {
+        RUST_UNORDERED_NODES.contains(node.kind())
+    }
+
     fn indexed_node_cognitive_complexity(&self, node: &IndexedNode) -> f64 {
         let mut res = 0.0;
-        let mut stack = vec![node];
-        while let Some(node) = stack.pop() {
-            for child in node.children() {
-                stack.push(child);
-            }
+        node.preorder_traverse(|node| {
             if let Some(&weight) = COGNITIVE_COMPLEXITY_WEIGHT.get(node.kind()) {
                 res += weight;
             }
-        }
+        });
         res
     }
 }
@@ -0,0 +1,187 @@
+use tree_sitter::{InputEdit, Point};
+
+/// Describes the change from `old` to `new` as a single edit of the range where they differ.
+///
+/// The edit spans from the end of the longest common prefix to the start of the longest
+/// common suffix that does not overlap it. This is exact for a single contiguous change, like
+/// typing or deleting text, and still correct but wider than needed for scattered changes.
+///
+/// # Arguments
+///
+/// * `old` - The source before the change.
+/// * `new` - The source after the change.
+///
+/// # Returns
+///
+/// The edit turning `old` into `new`, or `None` if they are equal.
+pub fn input_edit(old: &str, new: &str) -> Option<InputEdit> {
+    if old == new {
+        return None;
+    }
+
+    let mut prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(lhs, rhs)| lhs == rhs)
+        .count();
+    // Edits must start on a character boundary of both sources
+    while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(lhs, rhs)| lhs == rhs)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    let old_end_byte = old.len() - suffix;
+    let new_end_byte = new.len() - suffix;
+    Some(InputEdit {
+        start_byte: prefix,
+        old_end_byte,
+        new_end_byte,
+        start_position: point_at(old, prefix),
+        old_end_position: point_at(old, old_end_byte),
+        new_end_position: point_at(new, new_end_byte),
+    })
+}
+
+/// The byte ranges of the source after `edits` that the edits wrote, in the order the edits
+/// were made
+///
+/// Every edit is given in terms of the source left by the edits before it, so the ranges of
+/// earlier edits are moved along by the later ones.
+pub fn edited_ranges(edits: &[InputEdit]) -> Vec<(usize, usize)> {
+    edits
+        .iter()
+        .enumerate()
+        .map(|(i, edit)| {
+            edits[i + 1..].iter().fold(
+                (edit.start_byte, edit.new_end_byte),
+                |(start, end), later| (next_byte(later, start), next_byte(later, end)),
+            )
+        })
+        .collect()
+}
+
+/// Maps `byte` of the source after `edits` to the source before them
+///
+/// Bytes past an edit move back by what it wrote instead of what it replaced, bytes it wrote
+/// map to where it started.
+pub fn previous_byte(edits: &[InputEdit], byte: usize) -> usize {
+    edits.iter().rev().fold(byte, |byte, edit| {
+        if byte >= edit.new_end_byte {
+            byte - edit.new_end_byte + edit.old_end_byte
+        } else {
+            byte.min(edit.start_byte)
+        }
+    })
+}
+
+/// Maps `byte` of the source before `edit` to the source after it, bytes it replaced
+/// mapping to the end of what it wrote
+fn next_byte(edit: &InputEdit, byte: usize) -> usize {
+    if byte >= edit.old_end_byte {
+        byte - edit.old_end_byte + edit.new_end_byte
+    } else if byte > edit.start_byte {
+        edit.new_end_byte
+    } else {
+        byte
+    }
+}
+
+/// The row and byte column of `byte` in `text`
+fn point_at(text: &str, byte: usize) -> Point {
+    let before = &text.as_bytes()[..byte];
+    let row = before.iter().filter(|&&b| b == b'\n').count();
+    let column = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(byte, |newline| byte - newline - 1);
+    Point::new(row, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_edit_spans_the_changed_range() {
+        assert_eq!(input_edit("fn f() {}", "fn f() {}"), None);
+
+        let edit = input_edit("fn f() {\n    a();\n}", "fn f() {\n    a();\n    b();\n}").unwrap();
+        assert_eq!(
+            (edit.start_byte, edit.old_end_byte, edit.new_end_byte),
+            (18, 18, 27)
+        );
+        assert_eq!(edit.start_position, Point::new(2, 0));
+        assert_eq!(edit.old_end_position, Point::new(2, 0));
+        assert_eq!(edit.new_end_position, Point::new(3, 0));
+
+        // Deleting text
+        let edit = input_edit("let ab = 1;", "let a = 1;").unwrap();
+        assert_eq!(
+            (edit.start_byte, edit.old_end_byte, edit.new_end_byte),
+            (5, 6, 5)
+        );
+    }
+
+    #[test]
+    fn input_edit_keeps_to_character_boundaries() {
+        // "é" and "è" share their first byte, which the edit must not split
+        let edit = input_edit("let é = 1;", "let è = 1;").unwrap();
+        assert_eq!(
+            (edit.start_byte, edit.old_end_byte, edit.new_end_byte),
+            (4, 6, 6)
+        );
+        assert_eq!(edit.old_end_position, Point::new(0, 6));
+    }
+
+    #[test]
+    fn input_edit_does_not_let_prefix_and_suffix_overlap() {
+        // The common prefix and suffix of a repeated character would overlap
+        let edit = input_edit("aa", "aaa").unwrap();
+        assert_eq!(
+            (edit.start_byte, edit.old_end_byte, edit.new_end_byte),
+            (2, 2, 3)
+        );
+    }
+
+    fn edit(start_byte: usize, old_end_byte: usize, new_end_byte: usize) -> InputEdit {
+        InputEdit {
+            start_byte,
+            old_end_byte,
+            new_end_byte,
+            start_position: Point::default(),
+            old_end_position: Point::default(),
+            new_end_position: Point::default(),
+        }
+    }
+
+    #[test]
+    fn edited_ranges_move_along_with_later_edits() {
+        // Writes 3 bytes at 10, then replaces 2 bytes at 0 with 5
+        let edits = [edit(10, 10, 13), edit(0, 2, 5)];
+        assert_eq!(edited_ranges(&edits), vec![(13, 16), (0, 5)]);
+    }
+
+    #[test]
+    fn previous_byte_maps_back_through_every_edit() {
+        let edits = [edit(10, 10, 13), edit(0, 2, 5)];
+        // Before both edits
+        assert_eq!(previous_byte(&edits, 20), 14);
+        // Written by the first edit
+        assert_eq!(previous_byte(&edits, 14), 10);
+        // Written by the second edit
+        assert_eq!(previous_byte(&edits, 3), 0);
+        // Between the edits
+        assert_eq!(previous_byte(&edits, 8), 5);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{fmt, io, path::Path};
+
+use crate::engine::limits::{Limits, SkipReason};
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const UTF16_LE_BOM: &[u8] = b"\xFF\xFE";
+const UTF16_BE_BOM: &[u8] = b"\xFE\xFF";
+
+/// How the bytes of a source file were turned into text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-8 behind a byte order mark, which is dropped
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// Every byte is the character of the same code point, which never fails, so it is the
+    /// fallback for sources that are not valid UTF-8 without a byte order mark
+    Latin1,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Utf8 => write!(f, "UTF-8"),
+            Self::Utf8Bom => write!(f, "UTF-8 with BOM"),
+            Self::Utf16Le => write!(f, "UTF-16LE"),
+            Self::Utf16Be => write!(f, "UTF-16BE"),
+            Self::Latin1 => write!(f, "Latin-1"),
+        }
+    }
+}
+
+/// A source that claims an encoding by its byte order mark but is not valid in it
+///
+/// Sources without a byte order mark never fail to decode, since any bytes that are not valid
+/// UTF-8 are read as Latin-1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub encoding: Encoding,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not valid {}", self.encoding)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes the bytes of a source file into text
+///
+/// A byte order mark picks UTF-8 or UTF-16 and is dropped, so that positions count from the
+/// first character of the code. Without one, the bytes are read as UTF-8 if they are valid,
+/// or as Latin-1 otherwise.
+///
+/// # Returns
+///
+/// The text with the encoding it was read in, or an error if the bytes are not valid in the
+/// encoding their byte order mark claims.
+pub fn decode_source(bytes: Vec<u8>) -> Result<(String, Encoding), DecodeError> {
+    if let Some(rest) = bytes.strip_prefix(UTF8_BOM) {
+        return String::from_utf8(rest.to_vec())
+            .map(|source| (source, Encoding::Utf8Bom))
+            .map_err(|_| DecodeError {
+                encoding: Encoding::Utf8Bom,
+            });
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16_LE_BOM) {
+        return decode_utf16(rest, u16::from_le_bytes, Encoding::Utf16Le);
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16_BE_BOM) {
+        return decode_utf16(rest, u16::from_be_bytes, Encoding::Utf16Be);
+    }
+    match String::from_utf8(bytes) {
+        Ok(source) => Ok((source, Encoding::Utf8)),
+        Err(err) => {
+            let source = err.as_bytes().iter().map(|&byte| byte as char).collect();
+            Ok((source, Encoding::Latin1))
+        }
+    }
+}
+
+fn decode_utf16(
+    bytes: &[u8],
+    from_bytes: fn([u8; 2]) -> u16,
+    encoding: Encoding,
+) -> Result<(String, Encoding), DecodeError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(DecodeError { encoding });
+    }
+    let units = bytes
+        .chunks_exact(2)
+        .map(|pair| from_bytes([pair[0], pair[1]]));
+    char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .map(|source| (source, encoding))
+        .map_err(|_| DecodeError { encoding })
+}
+
+/// Drops the byte order mark text handed over by editors may still start with
+pub fn strip_bom(source: &str) -> &str {
+    source.strip_prefix('\u{FEFF}').unwrap_or(source)
+}
+
+/// Why a source file was not read
+#[derive(Debug)]
+pub enum ReadError {
+    /// The file is beyond the limits it was read under, as its size alone tells
+    Skipped(SkipReason),
+    /// The file could not be read, or could not be decoded, which is reported as
+    /// [`io::ErrorKind::InvalidData`]
+    Unreadable(io::Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Skipped(reason) => write!(f, "{reason}"),
+            Self::Unreadable(err) => write!(f, "could not be read: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Reads the file at `path` and decodes it with [`decode_source`]
+///
+/// Files larger than [`Limits::max_bytes`] are left unread, since the engine would skip them
+/// anyway.
+pub fn read_source(path: &Path, limits: &Limits) -> Result<String, ReadError> {
+    let len = std::fs::metadata(path)
+        .map_err(ReadError::Unreadable)?
+        .len();
+    limits
+        .check_size(usize::try_from(len).unwrap_or(usize::MAX))
+        .map_err(ReadError::Skipped)?;
+    let bytes = std::fs::read(path).map_err(ReadError::Unreadable)?;
+    decode_source(bytes)
+        .map(|(source, _)| source)
+        .map_err(|err| ReadError::Unreadable(io::Error::new(io::ErrorKind::InvalidData, err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2], bom: &[u8]) -> Vec<u8> {
+        let mut res = bom.to_vec();
+        res.extend(text.encode_utf16().flat_map(to_bytes));
+        res
+    }
+
+    #[test]
+    fn reads_utf8_with_and_without_bom() {
+        let source = "fn é() {}";
+        assert_eq!(
+            decode_source(source.as_bytes().to_vec()),
+            Ok((source.to_string(), Encoding::Utf8))
+        );
+        let bytes = [UTF8_BOM, source.as_bytes()].concat();
+        assert_eq!(
+            decode_source(bytes),
+            Ok((source.to_string(), Encoding::Utf8Bom))
+        );
+    }
+
+    #[test]
+    fn reads_utf16_in_both_byte_orders() {
+        let source = "fn 𝔣() {}";
+        assert_eq!(
+            decode_source(utf16(source, u16::to_le_bytes, UTF16_LE_BOM)),
+            Ok((source.to_string(), Encoding::Utf16Le))
+        );
+        assert_eq!(
+            decode_source(utf16(source, u16::to_be_bytes, UTF16_BE_BOM)),
+            Ok((source.to_string(), Encoding::Utf16Be))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_sources_behind_a_bom() {
+        let mut odd = utf16("fn f() {}", u16::to_le_bytes, UTF16_LE_BOM);
+        odd.push(b'x');
+        assert_eq!(
+            decode_source(odd),
+            Err(DecodeError {
+                encoding: Encoding::Utf16Le
+            })
+        );
+        // An unpaired surrogate
+        let unpaired = [UTF16_BE_BOM, &[0xD8, 0x00, 0x00, 0x41]].concat();
+        assert_eq!(
+            decode_source(unpaired),
+            Err(DecodeError {
+                encoding: Encoding::Utf16Be
+            })
+        );
+        let invalid = [UTF8_BOM, b"fn \xFF() {}"].concat();
+        assert_eq!(
+            decode_source(invalid),
+            Err(DecodeError {
+                encoding: Encoding::Utf8Bom
+            })
+        );
+    }
+
+    #[test]
+    fn falls_back_to_latin1_without_a_bom() {
+        assert_eq!(
+            decode_source(b"// caf\xE9\nfn f() {}".to_vec()),
+            Ok(("// café\nfn f() {}".to_string(), Encoding::Latin1))
+        );
+    }
+
+    #[test]
+    fn files_beyond_the_size_limit_are_left_unread() {
+        let path = std::env::temp_dir().join(format!("echolysis-read-{}.rs", std::process::id()));
+        std::fs::write(&path, "fn f() {}\n").unwrap();
+        let limits = |max_bytes| Limits {
+            max_bytes,
+            ..Limits::default()
+        };
+
+        let read = read_source(&path, &limits(Some(10)));
+        assert_eq!(read.unwrap(), "fn f() {}\n");
+        let read = read_source(&path, &limits(Some(9)));
+        assert!(matches!(
+            read,
+            Err(ReadError::Skipped(SkipReason::TooLarge {
+                bytes: 10,
+                limit: 9
+            }))
+        ));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            read_source(&path, &limits(None)),
+            Err(ReadError::Unreadable(_))
+        ));
+    }
+
+    #[test]
+    fn strips_bom_from_text() {
+        assert_eq!(strip_bom("\u{FEFF}fn f() {}"), "fn f() {}");
+        assert_eq!(strip_bom("fn f() {}"), "fn f() {}");
+    }
+}
@@ -1,3 +1,6 @@
+/// The seed used for hashing unless a language configuration sets another one
+pub const DEFAULT_SEED: u64 = 0x0123456789abcdef;
+
 /// Merges two 64-bit hash values into a single 64-bit hash value.
 ///
 /// This function takes two 64-bit hash values, `lhs` and `rhs`, and combines them
@@ -14,20 +17,23 @@
 ///
 /// A 64-bit hash value that represents the combined hash of `lhs` and `rhs`.
 pub fn merge_structure_hash(lhs: u64, rhs: u64) -> u64 {
-    // Initialize with a fixed seed value - a 64-bit hexadecimal constant
-    let seed: u64 = 0x0123456789abcdef;
+    merge_structure_hash_with_seed(DEFAULT_SEED, lhs, rhs)
+}
 
+/// Merges two 64-bit hash values like [`merge_structure_hash`], starting from `seed` instead
+/// of [`DEFAULT_SEED`].
+pub fn merge_structure_hash_with_seed(seed: u64, lhs: u64, rhs: u64) -> u64 {
     // First mixing step:
     // 1. Add 0x01 to the first hash to ensure non-zero
     // 2. Multiply seed by prime number 1000003 using wrapping multiplication to handle overflow
     // 3. XOR with the modified first hash
-    let mut value = seed.wrapping_mul(1000003) ^ (lhs + 0x01);
+    let mut value = seed.wrapping_mul(1000003) ^ lhs.wrapping_add(0x01);
 
     // Second mixing step:
     // 1. Add 0x02 to the second hash (different offset than first hash)
     // 2. Multiply previous value by same prime using wrapping multiplication
     // 3. XOR with the modified second hash
-    value = value.wrapping_mul(1000003) ^ (rhs + 0x02);
+    value = value.wrapping_mul(1000003) ^ rhs.wrapping_add(0x02);
 
     // Final mixing step - XOR with 2 to further scramble bits
     value ^= 2;
@@ -41,3 +47,55 @@ pub fn merge_structure_hash(lhs: u64, rhs: u64) -> u64 {
         value
     }
 }
+
+/// Hashes `bytes` with a seeded 64-bit FNV-1a followed by the splitmix64 finalizer.
+///
+/// Unlike the hashers of the standard library or `ahash`, the result only depends on `seed`
+/// and `bytes`, so it stays the same across processes, platforms and releases. This makes it
+/// suitable for hashes that are persisted or compared between runs.
+///
+/// # Arguments
+///
+/// * `seed` - Mixed into the FNV offset basis, so that different seeds give unrelated hashes.
+/// * `bytes` - The data to hash.
+///
+/// # Returns
+///
+/// A 64-bit hash value of `bytes`.
+pub fn stable_hash(seed: u64, bytes: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let mut value = FNV_OFFSET_BASIS ^ seed;
+    for &byte in bytes {
+        value ^= byte as u64;
+        value = value.wrapping_mul(FNV_PRIME);
+    }
+
+    // FNV-1a mixes its last bytes poorly, the splitmix64 finalizer spreads them over all bits
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_do_not_depend_on_the_process() {
+        // Pinned values: a change here breaks every persisted hash
+        assert_eq!(stable_hash(DEFAULT_SEED, b"total"), 0xe4e2344310074c39);
+        assert_eq!(merge_structure_hash(1, 2), 0x26af36c6e55b4c6b);
+        assert_eq!(merge_structure_hash_with_seed(7, 1, 2), 0x65dd322d343);
+    }
+
+    #[test]
+    fn seeds_give_unrelated_hashes() {
+        assert_ne!(stable_hash(1, b"total"), stable_hash(2, b"total"));
+        assert_ne!(
+            merge_structure_hash_with_seed(1, 1, 2),
+            merge_structure_hash_with_seed(2, 1, 2)
+        );
+    }
+}
@@ -1,2 +1,4 @@
+pub mod edit;
+pub mod encoding;
 pub mod hash;
 pub mod language_id;
@@ -4,11 +4,6 @@ mod server;
 
 #[tokio::main]
 async fn main() {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(8) // TODO: Make it configurable
-        .build_global()
-        .unwrap();
-
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
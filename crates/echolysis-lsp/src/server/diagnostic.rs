@@ -1,34 +1,116 @@
-use std::sync::Arc;
-
-use ahash::AHashMap;
-use echolysis_core::engine::indexed_node::IndexedNode;
+use ahash::{AHashMap, AHashSet};
+use echolysis_core::engine::{
+    cancel::CancellationToken,
+    duplicate_group::{DuplicateGroup, Origin},
+    parse_error::ParseError,
+    ranking::Ranking,
+};
 use tower_lsp::lsp_types;
 
-use super::{utils::get_node_location, Server};
+use super::{
+    utils::{get_fragment_location, point_to_position},
+    Server,
+};
 
 impl Server {
-    // Get all duplicate code fragments from engines
-    async fn collect_duplicates(&self) -> Vec<Vec<Arc<IndexedNode>>> {
-        self.router
-            .engines()
-            .iter()
+    // Get the duplicate code fragments to report from engines, or None if a newer pass cancelled
+    // this one
+    async fn collect_duplicates(&self, cancel: &CancellationToken) -> Option<Vec<DuplicateGroup>> {
+        let mut res = vec![];
+        for engine in self.router.engines().iter() {
+            // Only what changed since the last push is looked at again, and what a cancelled
+            // pass left out is looked at by the next one
+            let delta = engine.update_duplicates_cancellable(cancel);
+            let complete = delta.is_complete();
+            let delta = delta.into_inner();
+            // Engines report a change once, so changes of cancelled passes are kept for the
+            // next pass to publish
+            self.changed_groups.lock().extend(
+                delta
+                    .added()
+                    .iter()
+                    .chain(delta.removed())
+                    .chain(delta.changed())
+                    .cloned(),
+            );
+            if !complete {
+                return None;
+            }
             // TODO: make it configurable
-            .flat_map(|engine| engine.detect_duplicates(Some(100)))
+            let (nodes, sequences): (Vec<_>, Vec<_>) = engine
+                .duplicates(Ranking::default(), None)
+                .into_iter()
+                .partition(|group| group.origin() == Origin::Node);
+            res.extend(nodes.into_iter().take(100));
+            res.extend(sequences.into_iter().take(100));
+        }
+        Some(res)
+    }
+
+    // Files whose diagnostics may differ from the published ones: the files of groups that
+    // changed, and of groups that started or stopped being reported
+    fn touched_uris(&self, duplicates: &[DuplicateGroup]) -> AHashSet<lsp_types::Url> {
+        let changed = std::mem::take(&mut *self.changed_groups.lock());
+        let reported: AHashMap<_, _> = duplicates
+            .iter()
+            .map(|group| ((group.language(), group.id()), group.clone()))
+            .collect();
+        let previous = std::mem::replace(&mut *self.reported_groups.lock(), reported);
+        let reported = self.reported_groups.lock();
+
+        let key = |group: &DuplicateGroup| (group.language(), group.id());
+        let groups = changed
+            .iter()
+            .chain(changed.iter().filter_map(|group| previous.get(&key(group))))
+            .chain(
+                previous
+                    .values()
+                    .filter(|group| !reported.contains_key(&key(group))),
+            )
+            .chain(
+                reported
+                    .values()
+                    .filter(|group| !previous.contains_key(&key(group))),
+            );
+        groups
+            .flat_map(|group| group.fragments())
+            .filter_map(get_fragment_location)
+            .map(|location| location.uri)
             .collect()
     }
 
     // Create diagnostic for a duplicate code fragment
     fn create_duplicate_diagnostic(
+        group: &DuplicateGroup,
         location: &lsp_types::Location,
         other_locations: &[lsp_types::Location],
     ) -> lsp_types::Diagnostic {
-        let message = format!(
-            "Duplicated code fragments found in {} places",
-            other_locations.len()
+        let mut message = format!(
+            "Duplicated code fragments found in {} places ({} clone, {:.0}% similar)",
+            other_locations.len(),
+            group.similarity().kind(),
+            group.similarity().score() * 100.0,
         );
+        // Literals that differ are what a shared implementation would take as parameters
+        let literals: Vec<_> = group
+            .similarity()
+            .differing_literals()
+            .iter()
+            .map(|literals| {
+                let texts: Vec<_> = literals.iter().map(|literal| literal.text()).collect();
+                texts.join(" / ")
+            })
+            .collect();
+        if !literals.is_empty() {
+            message.push_str(&format!("\nDiffering literals: {}", literals.join(", ")));
+        }
         lsp_types::Diagnostic {
             range: location.range,
             severity: Some(lsp_types::DiagnosticSeverity::INFORMATION),
+            code: Some(lsp_types::NumberOrString::String(format!(
+                "{:016x}",
+                group.id()
+            ))),
             source: Some("echolysis".to_string()),
             message,
             related_information: Some(
@@ -51,16 +133,17 @@ impl Server {
     // Process a group of duplicates and update diagnostics map
     fn process_duplicate_group(
         &self,
-        group: &[Arc<IndexedNode>],
+        group: &DuplicateGroup,
         diagnostics_map: &mut AHashMap<lsp_types::Url, Vec<lsp_types::Diagnostic>>,
     ) {
         let locations: Vec<_> = group
+            .fragments()
             .iter()
-            .filter_map(|node| get_node_location(node))
+            .filter_map(get_fragment_location)
             .collect();
-        for node in group {
-            if let Some(location) = get_node_location(node) {
-                let diagnostic = Self::create_duplicate_diagnostic(&location, &locations);
+        for fragment in group.fragments() {
+            if let Some(location) = get_fragment_location(fragment) {
+                let diagnostic = Self::create_duplicate_diagnostic(group, &location, &locations);
                 diagnostics_map
                     .entry(location.uri.clone())
                     .or_default()
@@ -70,40 +153,87 @@ impl Server {
         }
     }
 
+    // Report where files did not parse, which their duplicates may depend on
+    fn process_parse_errors(
+        &self,
+        diagnostics_map: &mut AHashMap<lsp_types::Url, Vec<lsp_types::Diagnostic>>,
+    ) {
+        for engine in self.router.engines().iter() {
+            for (path, errors) in engine.parse_errors() {
+                let Ok(uri) = lsp_types::Url::from_file_path(path.as_path()) else {
+                    continue;
+                };
+                let diagnostics = diagnostics_map.entry(uri).or_default();
+                diagnostics.extend(errors.iter().map(Self::create_parse_error_diagnostic));
+            }
+        }
+    }
+
+    // Create diagnostic for a place a file did not parse
+    fn create_parse_error_diagnostic(error: &ParseError) -> lsp_types::Diagnostic {
+        let (start, end) = error.position_range;
+        let message = match error.missing {
+            Some(kind) => format!("Parse error, missing `{kind}`"),
+            None => "Parse error".to_string(),
+        };
+        lsp_types::Diagnostic {
+            range: lsp_types::Range {
+                start: point_to_position(&start),
+                end: point_to_position(&end),
+            },
+            severity: Some(lsp_types::DiagnosticSeverity::HINT),
+            code: Some(lsp_types::NumberOrString::String("parse-error".to_string())),
+            source: Some("echolysis".to_string()),
+            message,
+            ..Default::default()
+        }
+    }
+
     async fn publish_diagnostics(
         &self,
         diagnostics_map: AHashMap<lsp_types::Url, Vec<lsp_types::Diagnostic>>,
+        touched: &AHashSet<lsp_types::Url>,
     ) {
-        if diagnostics_map.is_empty() {
-            // Clear existing diagnostics
-            for item in self.diagnostics_uri_record.iter() {
-                self.client
-                    .publish_diagnostics(item.key().clone(), vec![], None)
-                    .await;
-            }
-            self.diagnostics_uri_record.clear();
-            return;
+        // Clear diagnostics of files that no longer have any
+        let cleared: Vec<_> = self
+            .diagnostics_uri_record
+            .iter()
+            .filter(|uri| !diagnostics_map.contains_key(uri.key()))
+            .map(|uri| uri.key().clone())
+            .collect();
+        for uri in cleared {
+            self.diagnostics_uri_record.remove(&uri);
+            self.client.publish_diagnostics(uri, vec![], None).await;
         }
 
-        // Publish new diagnostics
+        // Publish new diagnostics, and the ones of files cleared since the last push
         for (uri, diagnostics) in diagnostics_map {
-            self.diagnostics_uri_record.insert(uri.clone());
-            self.client
-                .publish_diagnostics(uri, diagnostics, None)
-                .await;
+            if self.diagnostics_uri_record.insert(uri.clone()) || touched.contains(&uri) {
+                self.client
+                    .publish_diagnostics(uri, diagnostics, None)
+                    .await;
+            }
         }
     }
 
     // Main function to process and publish diagnostics
     pub async fn push_diagnostic(&self) {
+        // A newer pass supersedes the one still running, which then publishes nothing
+        let cancel = CancellationToken::new();
+        std::mem::replace(&mut *self.diagnostic_pass.lock(), cancel.clone()).cancel();
+        let Some(duplicates) = self.collect_duplicates(&cancel).await else {
+            return;
+        };
+        let touched = self.touched_uris(&duplicates);
+
         self.duplicate_locations.lock().clear();
-        let duplicates = self.collect_duplicates().await;
 
         let mut diagnostics_map = AHashMap::new();
         for group in duplicates {
             self.process_duplicate_group(&group, &mut diagnostics_map);
         }
-        self.publish_diagnostics(diagnostics_map).await;
+        self.process_parse_errors(&mut diagnostics_map);
+        self.publish_diagnostics(diagnostics_map, &touched).await;
     }
 
     pub async fn clear_diagnostic(&self, uris: &[lsp_types::Url], version: Option<i32>) {
@@ -88,7 +88,7 @@ impl Server {
             .into_iter()
             .zip(std::iter::repeat(None))
             .collect::<Vec<_>>();
-        self.on_insert(&files).await;
+        self.on_insert(&files, &self.indexing_token()).await;
     }
 
     pub(super) async fn unwatch(&self, folders: &[lsp_types::WorkspaceFolder]) {
@@ -157,6 +157,7 @@ impl Server {
                         .into_iter()
                         .zip(std::iter::repeat(None))
                         .collect::<Vec<_>>(),
+                    &self.indexing_token(),
                 )
                 .await;
             }
@@ -169,13 +170,15 @@ impl Server {
             }
             EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
                 if let [from, to] = event.paths.as_slice() {
-                    let from = self.filter_map_fs_event_paths(&[from.clone()]);
-                    let to = self.filter_map_fs_event_paths(&[to.clone()]).pop();
+                    let from = self.filter_map_fs_event_paths(std::slice::from_ref(from));
+                    let to = self
+                        .filter_map_fs_event_paths(std::slice::from_ref(to))
+                        .pop();
                     if !from.is_empty() {
                         self.on_remove(&from).await;
                     }
                     if let Some(to) = to {
-                        self.on_insert(&[(to, None)]).await;
+                        self.on_insert(&[(to, None)], &self.indexing_token()).await;
                     }
                 }
             }
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use tower_lsp::{
     jsonrpc,
@@ -6,6 +6,11 @@ use tower_lsp::{
     LanguageServer,
 };
 
+use echolysis_core::{
+    engine::parse_error::ParseErrorPolicy,
+    languages::{Normalization, Thresholds},
+};
+
 use super::Server;
 
 #[tower_lsp::async_trait]
@@ -17,6 +22,128 @@ impl LanguageServer for Server {
         self.stopped
             .store(false, std::sync::atomic::Ordering::SeqCst);
 
+        // Lets the CLI and the server share indexed files, see `initializationOptions.cacheDir`
+        let cache_dir = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("cacheDir")?.as_str())
+            .map(PathBuf::from);
+        self.router.set_cache_dir(cache_dir);
+        // Sizes the pool engines run in, see `initializationOptions.threads`
+        if let Some(threads) = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("threads")?.as_u64())
+        {
+            self.router.set_threads(threads as usize);
+        }
+
+        // Overrides the default limits field by field, see `initializationOptions.limits`
+        if let Some(options) = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("limits"))
+        {
+            let mut limits = self.router.limits();
+            // `null` lifts a limit
+            let limit = |key: &str, default: Option<usize>| match options.get(key) {
+                Some(value) if value.is_null() => None,
+                Some(value) => value.as_u64().map(|x| x as usize).or(default),
+                None => default,
+            };
+            limits.max_bytes = limit("maxBytes", limits.max_bytes);
+            limits.max_line_length = limit("maxLineLength", limits.max_line_length);
+            limits.max_nodes = limit("maxNodes", limits.max_nodes);
+            if let Some(skip) = options.get("skipMinified").and_then(|x| x.as_bool()) {
+                limits.skip_minified = skip;
+            }
+            self.router.set_limits(limits);
+        }
+
+        // What to do with files that do not parse, see `initializationOptions.parseErrors`
+        if let Some(policy) = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("parseErrors")?.as_str())
+        {
+            self.router.set_parse_error_policy(match policy {
+                "skipFile" => ParseErrorPolicy::SkipFile,
+                "index" => ParseErrorPolicy::Index,
+                _ => ParseErrorPolicy::SkipSubtrees,
+            });
+        }
+
+        // How strictly fragments must match, see `initializationOptions.normalization`
+        if let Some(normalization) = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("normalization")?.as_str())
+        {
+            let mut config = self.router.language_config();
+            config.normalization = match normalization {
+                "exact" => Normalization::Exact,
+                "abstracted" => Normalization::Abstracted,
+                _ => Normalization::Renamed,
+            };
+            self.router.set_language_config(config);
+        }
+        // Minimum size of reported fragments, see `initializationOptions.thresholds`
+        if let Some(options) = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("thresholds"))
+        {
+            let mut config = self.router.language_config();
+            // Overrides `base` field by field
+            let thresholds = |options: &lsp_types::LSPAny, base: Thresholds| {
+                let count = |key: &str, default: usize| {
+                    options
+                        .get(key)
+                        .and_then(|x| x.as_u64())
+                        .map_or(default, |x| x as usize)
+                };
+                Thresholds {
+                    complexity: options
+                        .get("complexity")
+                        .and_then(|x| x.as_f64())
+                        .unwrap_or(base.complexity),
+                    lines: count("lines", base.lines),
+                    tokens: count("tokens", base.tokens),
+                    nodes: count("nodes", base.nodes),
+                }
+            };
+            let base = thresholds(options, config.thresholds.unwrap_or_default());
+            config.thresholds = Some(base);
+            // Thresholds by node kind, over the ones above
+            if let Some(kinds) = options.get("kinds").and_then(|x| x.as_object()) {
+                for (kind, options) in kinds {
+                    config
+                        .kind_thresholds
+                        .insert(kind.clone(), thresholds(options, base));
+                }
+            }
+            self.router.set_language_config(config);
+        }
+        // Whether groups are split by consistent renaming, see
+        // `initializationOptions.consistentRenaming`
+        if let Some(consistent_renaming) = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("consistentRenaming")?.as_bool())
+        {
+            self.router.set_consistent_renaming(consistent_renaming);
+        }
+        // Whether literals are compared by kind, see `initializationOptions.abstractLiterals`
+        if let Some(abstract_literals) = params
+            .initialization_options
+            .as_ref()
+            .and_then(|options| options.get("abstractLiterals")?.as_bool())
+        {
+            let mut config = self.router.language_config();
+            config.abstract_literals = abstract_literals;
+            self.router.set_language_config(config);
+        }
+
         self.watch(&params.workspace_folders.unwrap_or_default())
             .await;
 
@@ -52,10 +179,13 @@ impl LanguageServer for Server {
     }
 
     async fn did_open(&self, params: lsp_types::DidOpenTextDocumentParams) {
-        self.on_insert(&[(
-            params.text_document.uri,
-            Some(Arc::new(params.text_document.text)),
-        )])
+        self.on_insert(
+            &[(
+                params.text_document.uri,
+                Some(Arc::new(params.text_document.text)),
+            )],
+            &self.indexing_token(),
+        )
         .await;
     }
 
@@ -73,10 +203,13 @@ impl LanguageServer for Server {
         let last = LAST_CHANGE.lock().replace(params);
         match last {
             Some(mut last) if last.text_document.uri != uri => {
-                self.on_insert(&[(
-                    last.text_document.uri,
-                    Some(Arc::new(last.content_changes.swap_remove(0).text)),
-                )])
+                self.on_insert(
+                    &[(
+                        last.text_document.uri,
+                        Some(Arc::new(last.content_changes.swap_remove(0).text)),
+                    )],
+                    &self.indexing_token(),
+                )
                 .await;
             }
             _ => (),
@@ -92,10 +225,13 @@ impl LanguageServer for Server {
             _ => {
                 let last = LAST_CHANGE.lock().take();
                 if let Some(mut last) = last {
-                    self.on_insert(&[(
-                        last.text_document.uri,
-                        Some(Arc::new(last.content_changes.swap_remove(0).text)),
-                    )])
+                    self.on_insert(
+                        &[(
+                            last.text_document.uri,
+                            Some(Arc::new(last.content_changes.swap_remove(0).text)),
+                        )],
+                        &self.indexing_token(),
+                    )
                     .await;
                 }
             }
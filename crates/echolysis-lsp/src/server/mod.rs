@@ -3,7 +3,9 @@ use std::{
     time::Duration,
 };
 
+use ahash::AHashMap;
 use dashmap::{DashMap, DashSet};
+use echolysis_core::engine::{cancel::CancellationToken, duplicate_group::DuplicateGroup};
 use fs_watcher::FsWatcher;
 use router::Router;
 use tower_lsp::lsp_types::{self, MessageType};
@@ -21,6 +23,14 @@ pub struct Server {
 
     diagnostics_uri_record: DashSet<lsp_types::Url>,
     duplicate_locations: parking_lot::Mutex<Vec<Vec<lsp_types::Location>>>,
+    /// Groups the published diagnostics come from, by language and group id
+    reported_groups: parking_lot::Mutex<AHashMap<(&'static str, u64), DuplicateGroup>>,
+    /// Groups that changed since the diagnostics were last published
+    changed_groups: parking_lot::Mutex<Vec<DuplicateGroup>>,
+    /// Cancels the diagnostic pass in progress
+    diagnostic_pass: parking_lot::Mutex<CancellationToken>,
+    /// Cancels the inserts in progress, including workspace scans, when the server is cleared
+    indexing: parking_lot::Mutex<CancellationToken>,
 
     /// K: file path, V: language id
     file_map: DashMap<lsp_types::Url, &'static str>,
@@ -80,6 +90,10 @@ impl Server {
             file_map: DashMap::default(),
             diagnostics_uri_record: DashSet::default(),
             duplicate_locations: parking_lot::Mutex::new(vec![]),
+            reported_groups: parking_lot::Mutex::new(AHashMap::new()),
+            changed_groups: parking_lot::Mutex::new(vec![]),
+            diagnostic_pass: parking_lot::Mutex::new(CancellationToken::new()),
+            indexing: parking_lot::Mutex::new(CancellationToken::new()),
             stopped: AtomicBool::new(false),
         });
 
@@ -88,7 +102,13 @@ impl Server {
         server
     }
 
+    /// Token of the inserts started until the server is next cleared
+    fn indexing_token(&self) -> CancellationToken {
+        self.indexing.lock().clone()
+    }
+
     pub async fn clear(&self) {
+        std::mem::take(&mut *self.indexing.lock()).cancel();
         self.fs_watcher.clear();
         self.file_map.clear();
         self.router.clear(); // Clear router before clear diagnostics
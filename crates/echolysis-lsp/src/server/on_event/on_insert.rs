@@ -1,7 +1,13 @@
 use std::{path::PathBuf, sync::Arc};
 
-use echolysis_core::utils::language_id::get_language_id_by_path;
-use rayon::prelude::*;
+use echolysis_core::{
+    engine::cancel::CancellationToken,
+    utils::{
+        encoding::{read_source, strip_bom},
+        language_id::get_language_id_by_path,
+    },
+};
+use rayon::{iter::Either, prelude::*};
 use rustc_hash::FxHashMap;
 use tower_lsp::lsp_types;
 
@@ -33,8 +39,13 @@ impl Server {
         lang_map
     }
 
-    pub async fn on_insert(&self, sources: &[(lsp_types::Url, Option<Arc<String>>)]) {
-        if self.is_stopped() {
+    /// Indexes `sources` until `cancel` is cancelled, reading the files given without content
+    pub async fn on_insert(
+        &self,
+        sources: &[(lsp_types::Url, Option<Arc<String>>)],
+        cancel: &CancellationToken,
+    ) {
+        if self.is_stopped() || cancel.is_cancelled() {
             return;
         }
 
@@ -50,21 +61,60 @@ impl Server {
             return;
         }
 
+        let mut skipped = vec![];
         lang_map.into_iter().for_each(|(lang, sources)| {
+            if cancel.is_cancelled() {
+                return;
+            }
             if let Some(engine) = self.router.get_engine_by_language_id(lang) {
-                let sources = sources
-                    .into_par_iter()
-                    .filter_map(|(path, source)| {
-                        let source = source
-                            .clone()
-                            .unwrap_or(Arc::new(std::fs::read_to_string(&path).ok()?));
-                        Some((Arc::new(path), source))
-                    })
-                    .collect::<Vec<_>>();
-                engine.insert_many(sources);
+                let limits = engine.limits();
+                let (sources, unread): (Vec<_>, Vec<_>) = self.router.thread_pool().install(|| {
+                    sources
+                        .into_par_iter()
+                        .filter(|_| !cancel.is_cancelled())
+                        .map(|(path, source)| {
+                            let path = Arc::new(path);
+                            match source {
+                                Some(source) if strip_bom(source).len() == source.len() => {
+                                    Either::Left((path, source.clone()))
+                                }
+                                Some(source) => {
+                                    let source = strip_bom(source).to_string();
+                                    Either::Left((path, Arc::new(source)))
+                                }
+                                None => match read_source(&path, limits) {
+                                    Ok(source) => Either::Left((path, Arc::new(source))),
+                                    Err(err) => Either::Right((path, err.to_string())),
+                                },
+                            }
+                        })
+                        .partition_map(|x| x)
+                });
+                // Keeps no stale duplicates for files that are gone or can no longer be read
+                for (path, _) in &unread {
+                    engine.remove(path.clone());
+                }
+                skipped.extend(unread);
+                skipped.extend(
+                    engine
+                        .insert_many_cancellable(sources, cancel)
+                        .into_inner()
+                        .into_iter()
+                        .map(|(path, reason)| (path, reason.to_string())),
+                );
             }
         });
+        for (path, reason) in skipped {
+            self.client
+                .log_message(
+                    lsp_types::MessageType::INFO,
+                    format!("skipped {}: it {reason}", path.display()),
+                )
+                .await;
+        }
 
-        self.push_diagnostic().await;
+        if !cancel.is_cancelled() {
+            self.push_diagnostic().await;
+        }
     }
 }
@@ -1,21 +1,45 @@
 #![allow(unused)]
 
-use std::{path::Path, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use dashmap::DashMap;
 use echolysis_core::{
-    engine::Engine, languages::SupportedLanguage, utils::language_id::get_language_id_by_path,
+    engine::{limits::Limits, parse_error::ParseErrorPolicy, Engine},
+    languages::{LanguageConfig, SupportedLanguage},
+    utils::language_id::get_language_id_by_path,
 };
+use rayon::ThreadPool;
 
 pub struct Router {
     // K: language_id, V: Engine
     engines: DashMap<String, Arc<Engine>, ahash::RandomState>,
+    // Shared by all engines created after it is set
+    cache_dir: parking_lot::RwLock<Option<PathBuf>>,
+    // Bounds the files indexed by all engines created after they are set
+    limits: parking_lot::RwLock<Limits>,
+    parse_error_policy: parking_lot::RwLock<ParseErrorPolicy>,
+    consistent_renaming: AtomicBool,
+    language_config: parking_lot::RwLock<LanguageConfig>,
+    // Runs the parallel work of all engines created after it is set
+    thread_pool: parking_lot::RwLock<Arc<ThreadPool>>,
 }
 
 impl Router {
     pub fn new() -> Self {
         Self {
             engines: DashMap::with_hasher(ahash::RandomState::default()),
+            cache_dir: parking_lot::RwLock::new(None),
+            limits: parking_lot::RwLock::new(Limits::default()),
+            parse_error_policy: parking_lot::RwLock::new(ParseErrorPolicy::default()),
+            consistent_renaming: AtomicBool::new(false),
+            language_config: parking_lot::RwLock::new(LanguageConfig::default()),
+            thread_pool: parking_lot::RwLock::new(Self::build_thread_pool(0)),
         }
     }
 
@@ -23,17 +47,75 @@ impl Router {
         &self.engines
     }
 
+    pub fn set_cache_dir(&self, cache_dir: Option<PathBuf>) {
+        *self.cache_dir.write() = cache_dir;
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write() = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits.read().clone()
+    }
+
+    pub fn set_parse_error_policy(&self, policy: ParseErrorPolicy) {
+        *self.parse_error_policy.write() = policy;
+    }
+
+    pub fn set_consistent_renaming(&self, consistent_renaming: bool) {
+        self.consistent_renaming
+            .store(consistent_renaming, Ordering::Relaxed);
+    }
+
+    pub fn set_language_config(&self, config: LanguageConfig) {
+        *self.language_config.write() = config;
+    }
+
+    pub fn language_config(&self) -> LanguageConfig {
+        self.language_config.read().clone()
+    }
+
+    /// Sizes the thread pool of engines created from now on, zero meaning one thread per CPU
+    pub fn set_threads(&self, threads: usize) {
+        *self.thread_pool.write() = Self::build_thread_pool(threads);
+    }
+
+    pub fn thread_pool(&self) -> Arc<ThreadPool> {
+        self.thread_pool.read().clone()
+    }
+
+    fn build_thread_pool(threads: usize) -> Arc<ThreadPool> {
+        Arc::new(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap(),
+        )
+    }
+
     pub fn get_engine_by_path(&self, path: &Path) -> Option<Arc<Engine>> {
         let language_id = get_language_id_by_path(path).to_string();
         self.get_engine_by_language_id(&language_id)
     }
 
     pub fn get_engine_by_language_id(&self, language_id: &str) -> Option<Arc<Engine>> {
-        let language = SupportedLanguage::from_language_id(language_id)?;
+        let language =
+            SupportedLanguage::from_language_id_with_config(language_id, self.language_config())?;
         Some(
             self.engines
                 .entry(language_id.to_string())
-                .or_insert_with(|| Arc::new(Engine::new(language)))
+                .or_insert_with(|| {
+                    let mut builder = Engine::builder(language)
+                        .limits(self.limits())
+                        .parse_error_policy(*self.parse_error_policy.read())
+                        .consistent_renaming(self.consistent_renaming.load(Ordering::Relaxed))
+                        .thread_pool(self.thread_pool());
+                    if let Some(cache_dir) = self.cache_dir.read().clone() {
+                        builder = builder.cache_dir(cache_dir);
+                    }
+                    Arc::new(builder.build())
+                })
                 .value()
                 .clone(),
         )
@@ -1,7 +1,7 @@
 use std::path::{Path, PathBuf};
 
 use echolysis_core::{
-    engine::indexed_node::IndexedNode, languages::SupportedLanguage,
+    engine::fragment::Fragment, languages::SupportedLanguage,
     utils::language_id::get_language_id_by_path,
 };
 use tower_lsp::lsp_types;
@@ -11,9 +11,9 @@ pub fn point_to_position(point: &echolysis_core::Point) -> lsp_types::Position {
     lsp_types::Position::new(point.row as u32, point.column as u32)
 }
 
-pub fn get_node_location(node: &IndexedNode) -> Option<lsp_types::Location> {
-    let uri = lsp_types::Url::from_file_path(node.path()).ok()?;
-    let (start, end) = node.position_range();
+pub fn get_fragment_location(fragment: &Fragment) -> Option<lsp_types::Location> {
+    let uri = lsp_types::Url::from_file_path(fragment.path()).ok()?;
+    let (start, end) = fragment.position_range();
     Some(lsp_types::Location {
         uri,
         range: lsp_types::Range {